@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE jobs
+    DROP COLUMN IF EXISTS downloaded_files,
+    DROP COLUMN IF EXISTS downloaded_bytes;
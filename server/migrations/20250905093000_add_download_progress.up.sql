@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE jobs
+    ADD COLUMN downloaded_files BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN downloaded_bytes BIGINT NOT NULL DEFAULT 0;
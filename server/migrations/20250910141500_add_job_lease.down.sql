@@ -0,0 +1,7 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS jobs_lease_expires_at_idx;
+
+ALTER TABLE jobs
+    DROP COLUMN IF EXISTS claimed_by,
+    DROP COLUMN IF EXISTS lease_expires_at,
+    DROP COLUMN IF EXISTS attempts;
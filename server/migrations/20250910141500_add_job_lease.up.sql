@@ -0,0 +1,7 @@
+-- Add up migration script here
+ALTER TABLE jobs
+    ADD COLUMN claimed_by TEXT,
+    ADD COLUMN lease_expires_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX jobs_lease_expires_at_idx ON jobs (lease_expires_at) WHERE lease_expires_at IS NOT NULL;
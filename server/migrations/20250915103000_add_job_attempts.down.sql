@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS jobs_record_attempt ON jobs;
+DROP FUNCTION IF EXISTS record_job_attempt();
+DROP TABLE IF EXISTS job_attempts;
@@ -0,0 +1,48 @@
+-- Add up migration script here
+CREATE TABLE job_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    attempt INTEGER NOT NULL,
+    job_status TEXT NOT NULL,
+    worker TEXT,
+    input JSONB NOT NULL,
+    output JSONB,
+    error JSONB,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    ended_at TIMESTAMP WITH TIME ZONE,
+    UNIQUE (job_id, attempt)
+);
+
+-- Opens an attempt when a job starts running and closes it when the job leaves the active
+-- states, so attempts are recorded whichever process drives the job.
+CREATE FUNCTION record_job_attempt() RETURNS trigger AS $$
+BEGIN
+    IF NEW.job_status = 'running' AND OLD.job_status IS DISTINCT FROM 'running' THEN
+        INSERT INTO job_attempts (job_id, attempt, job_status, worker, input)
+        VALUES (
+            NEW.id,
+            (SELECT COALESCE(MAX(attempt), 0) + 1 FROM job_attempts WHERE job_id = NEW.id),
+            NEW.job_status,
+            NEW.claimed_by,
+            NEW.input
+        );
+    ELSIF NEW.job_status IN ('completed', 'failed', 'cancelled') AND OLD.job_status IS DISTINCT FROM NEW.job_status THEN
+        UPDATE job_attempts
+        SET job_status = NEW.job_status, output = NEW.output, error = NEW.error, ended_at = now()
+        WHERE job_id = NEW.id AND ended_at IS NULL;
+    ELSIF NEW.job_status = 'pending' AND OLD.job_status IN ('running', 'completing', 'uploading', 'cancelling') THEN
+        UPDATE job_attempts
+        SET job_status = 'failed',
+            output = NEW.output,
+            error = jsonb_build_object('code', 'lease_expired', 'message', 'Job lease expired'),
+            ended_at = now()
+        WHERE job_id = NEW.id AND ended_at IS NULL;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_record_attempt
+    AFTER UPDATE OF job_status ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION record_job_attempt();
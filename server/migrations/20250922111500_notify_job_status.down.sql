@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS jobs_notify_status ON jobs;
+DROP FUNCTION IF EXISTS notify_job_status();
@@ -0,0 +1,26 @@
+-- Add up migration script here
+CREATE FUNCTION notify_job_status() RETURNS trigger AS $$
+DECLARE
+    previous_status TEXT;
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        IF OLD.job_status IS NOT DISTINCT FROM NEW.job_status THEN
+            RETURN NEW;
+        END IF;
+        previous_status := OLD.job_status;
+    END IF;
+    PERFORM pg_notify('job_status', json_build_object(
+        'id', NEW.id,
+        'status', NEW.job_status,
+        'previous_status', previous_status,
+        'job_type', NEW.job_type,
+        'domain_id', NEW.domain_id
+    )::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_notify_status
+    AFTER INSERT OR UPDATE OF job_status ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_job_status();
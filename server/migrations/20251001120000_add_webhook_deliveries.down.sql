@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS jobs_enqueue_webhook ON jobs;
+DROP FUNCTION IF EXISTS enqueue_job_webhook();
+DROP TABLE IF EXISTS webhook_deliveries;
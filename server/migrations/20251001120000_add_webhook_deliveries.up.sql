@@ -0,0 +1,48 @@
+-- Add up migration script here
+CREATE TABLE webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT,
+    payload JSONB NOT NULL,
+    delivery_status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    response_status INTEGER,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX webhook_deliveries_due_idx ON webhook_deliveries (next_attempt_at) WHERE delivery_status = 'pending';
+CREATE INDEX webhook_deliveries_job_id_idx ON webhook_deliveries (job_id);
+
+-- Queues a delivery in the same transaction that finishes the job, so the result is sent
+-- whichever process completed or failed it.
+CREATE FUNCTION enqueue_job_webhook() RETURNS trigger AS $$
+BEGIN
+    IF NEW.job_status IN ('completed', 'failed')
+        AND OLD.job_status IS DISTINCT FROM NEW.job_status
+        AND COALESCE(NEW.input->>'webhook_url', '') <> '' THEN
+        INSERT INTO webhook_deliveries (job_id, url, secret, payload)
+        VALUES (
+            NEW.id,
+            NEW.input->>'webhook_url',
+            NULLIF(NEW.input->>'webhook_secret', ''),
+            jsonb_build_object(
+                'job_id', NEW.id,
+                'status', NEW.job_status,
+                'data', CASE WHEN NEW.job_status = 'completed' THEN NEW.output END,
+                'error', CASE WHEN NEW.job_status = 'failed' THEN NEW.error END
+            )
+        );
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_enqueue_webhook
+    AFTER UPDATE OF job_status ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION enqueue_job_webhook();
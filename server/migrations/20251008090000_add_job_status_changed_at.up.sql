@@ -0,0 +1,68 @@
+-- Add up migration script here
+ALTER TABLE jobs ADD COLUMN status_changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+UPDATE jobs SET status_changed_at = COALESCE(updated_at, created_at, now());
+
+-- Stamps when a job entered its current status
+CREATE FUNCTION set_job_status_changed_at() RETURNS trigger AS $$
+BEGIN
+    IF OLD.job_status IS DISTINCT FROM NEW.job_status THEN
+        NEW.status_changed_at := now();
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_status_changed_at
+    BEFORE UPDATE OF job_status ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION set_job_status_changed_at();
+
+-- Status changes also tell how long the job spent in its previous status
+DROP TRIGGER IF EXISTS jobs_notify_status ON jobs;
+
+CREATE OR REPLACE FUNCTION notify_job_status() RETURNS trigger AS $$
+DECLARE
+    previous_status TEXT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM pg_notify('job_status', json_build_object(
+            'event', 'created',
+            'id', NEW.id,
+            'status', NEW.job_status,
+            'previous_status', NULL,
+            'job_type', NEW.job_type,
+            'domain_id', NEW.domain_id
+        )::text);
+        RETURN NEW;
+    END IF;
+
+    IF OLD.job_status IS DISTINCT FROM NEW.job_status THEN
+        previous_status := OLD.job_status;
+        PERFORM pg_notify('job_status', json_build_object(
+            'event', 'status_changed',
+            'id', NEW.id,
+            'status', NEW.job_status,
+            'previous_status', previous_status,
+            'previous_status_seconds', EXTRACT(EPOCH FROM NEW.status_changed_at - OLD.status_changed_at),
+            'job_type', NEW.job_type,
+            'domain_id', NEW.domain_id
+        )::text);
+    END IF;
+    IF OLD.output IS NULL AND NEW.output IS NOT NULL THEN
+        PERFORM pg_notify('job_status', json_build_object(
+            'event', 'output_ready',
+            'id', NEW.id,
+            'status', NEW.job_status,
+            'previous_status', OLD.job_status,
+            'job_type', NEW.job_type,
+            'domain_id', NEW.domain_id
+        )::text);
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_notify_status
+    AFTER INSERT OR UPDATE OF job_status, output ON jobs
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_job_status();
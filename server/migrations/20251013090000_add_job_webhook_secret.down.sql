@@ -0,0 +1,28 @@
+-- Add down migration script here
+CREATE OR REPLACE FUNCTION enqueue_job_webhook() RETURNS trigger AS $$
+BEGIN
+    IF NEW.job_status IN ('completed', 'failed')
+        AND OLD.job_status IS DISTINCT FROM NEW.job_status
+        AND COALESCE(NEW.input->>'webhook_url', '') <> '' THEN
+        INSERT INTO webhook_deliveries (job_id, url, secret, payload)
+        VALUES (
+            NEW.id,
+            NEW.input->>'webhook_url',
+            NULLIF(NEW.input->>'webhook_secret', ''),
+            jsonb_build_object(
+                'job_id', NEW.id,
+                'status', NEW.job_status,
+                'data', CASE WHEN NEW.job_status = 'completed' THEN NEW.output END,
+                'error', CASE WHEN NEW.job_status = 'failed' THEN NEW.error END
+            )
+        );
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+UPDATE jobs
+SET input = jsonb_set(input, '{webhook_secret}', to_jsonb(webhook_secret))
+WHERE webhook_secret IS NOT NULL;
+
+ALTER TABLE jobs DROP COLUMN webhook_secret;
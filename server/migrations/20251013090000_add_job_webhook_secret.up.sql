@@ -0,0 +1,34 @@
+-- Add up migration script here
+-- Webhook secrets are kept out of `input`, which is returned by the API, sent in job events
+-- and copied into every attempt.
+ALTER TABLE jobs ADD COLUMN webhook_secret TEXT;
+
+UPDATE jobs
+SET webhook_secret = NULLIF(input->>'webhook_secret', ''), input = input - 'webhook_secret'
+WHERE input ? 'webhook_secret';
+
+UPDATE job_attempts
+SET input = input - 'webhook_secret'
+WHERE input ? 'webhook_secret';
+
+CREATE OR REPLACE FUNCTION enqueue_job_webhook() RETURNS trigger AS $$
+BEGIN
+    IF NEW.job_status IN ('completed', 'failed')
+        AND OLD.job_status IS DISTINCT FROM NEW.job_status
+        AND COALESCE(NEW.input->>'webhook_url', '') <> '' THEN
+        INSERT INTO webhook_deliveries (job_id, url, secret, payload)
+        VALUES (
+            NEW.id,
+            NEW.input->>'webhook_url',
+            NULLIF(NEW.webhook_secret, ''),
+            jsonb_build_object(
+                'job_id', NEW.id,
+                'status', NEW.job_status,
+                'data', CASE WHEN NEW.job_status = 'completed' THEN NEW.output END,
+                'error', CASE WHEN NEW.job_status = 'failed' THEN NEW.error END
+            )
+        );
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
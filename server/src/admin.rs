@@ -0,0 +1,297 @@
+use std::ffi::CString;
+use std::io;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse, Responder};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+
+use crate::{config, domain, host_pool::HostPool, inference::{missing_models, same_model, InferenceBackend, ModelNotFound}, metrics, pg};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Config {
+    /// Bytes that must be free in `DATA_DIR` for the server to be ready.
+    pub min_free_disk: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Config {
+            min_free_disk: std::env::var("READY_MIN_FREE_DISK_MB").unwrap_or("1024".to_string()).parse::<u64>()? * 1024 * 1024,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    /// Working, but something needs attention. Does not make the server unready.
+    Degraded,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+struct Component {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    details: serde_json::Value,
+}
+
+impl Component {
+    fn new(status: Status, error: Option<String>, details: serde_json::Value) -> Self {
+        Component { status, error, details }
+    }
+}
+
+async fn check_postgres(pool: &PgPool) -> Component {
+    let started = Instant::now();
+    let res = tokio::time::timeout(CHECK_TIMEOUT, pg::ping(pool)).await;
+    let details = json!({
+        "latency_ms": started.elapsed().as_millis() as u64,
+        "connections": pool.size(),
+        "idle_connections": pool.num_idle(),
+    });
+    match res {
+        Ok(Ok(())) => Component::new(Status::Ok, None, details),
+        Ok(Err(e)) => Component::new(Status::Error, Some(e.to_string()), details),
+        Err(_) => Component::new(Status::Error, Some(format!("Timed out after {:?}", CHECK_TIMEOUT)), details),
+    }
+}
+
+async fn check_inference(host_pool: &HostPool, vlm_config: &config::Config) -> Component {
+    let hosts = host_pool.probe_all().await;
+    let missing = missing_models(host_pool, vlm_config).await;
+    let healthy = hosts.iter().filter(|host| host.healthy).count();
+    let (status, error) = if healthy == 0 {
+        (Status::Error, Some("No inference host is reachable".to_string()))
+    } else if !missing.is_empty() {
+        (Status::Error, Some(format!("Missing models: {}", missing.join(", "))))
+    } else if healthy < hosts.len() {
+        (Status::Degraded, Some(format!("{} of {} inference hosts are unreachable", hosts.len() - healthy, hosts.len())))
+    } else {
+        (Status::Ok, None)
+    };
+    Component::new(status, error, json!({ "hosts": hosts, "missing_models": missing }))
+}
+
+/// Failed calls only degrade the domain client, they may be caused by a single bad `domain_id`.
+fn check_domain() -> Component {
+    let status = domain::status();
+    if status.is_failing() {
+        Component::new(Status::Degraded, status.last_error.clone(), json!({ "last_success_at": status.last_success_at, "last_error_at": status.last_error_at }))
+    } else {
+        Component::new(Status::Ok, None, json!({ "last_success_at": status.last_success_at }))
+    }
+}
+
+fn free_disk_space(path: &str) -> io::Result<u64> {
+    let path = CString::new(path)?;
+    // SAFETY: `statvfs` is plain data, zeroes are a valid value for it to overwrite
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL terminated and `stat` outlives the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+async fn check_disk(data_dir: &str, admin_config: &Config) -> Component {
+    let path = data_dir.to_string();
+    let free = tokio::task::spawn_blocking(move || free_disk_space(&path))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    match free {
+        Ok(free) => {
+            let details = json!({ "path": data_dir, "free_bytes": free, "min_free_bytes": admin_config.min_free_disk });
+            if free < admin_config.min_free_disk {
+                Component::new(Status::Error, Some("Not enough free disk space".to_string()), details)
+            } else {
+                Component::new(Status::Ok, None, details)
+            }
+        }
+        Err(e) => Component::new(Status::Error, Some(e.to_string()), json!({ "path": data_dir })),
+    }
+}
+
+/// Checks every dependency at once. The server is ready if none of them is in error.
+async fn check_all(
+    pool: &PgPool,
+    host_pool: &HostPool,
+    vlm_config: &config::Config,
+    data_dir: &str,
+    admin_config: &Config,
+) -> (bool, serde_json::Value) {
+    let (postgres, inference, disk) = tokio::join!(
+        check_postgres(pool),
+        check_inference(host_pool, vlm_config),
+        check_disk(data_dir, admin_config),
+    );
+    let domain = check_domain();
+    let statuses = [postgres.status, inference.status, domain.status, disk.status];
+    let status = if statuses.contains(&Status::Error) {
+        Status::Error
+    } else if statuses.contains(&Status::Degraded) {
+        Status::Degraded
+    } else {
+        Status::Ok
+    };
+    let report = json!({
+        "status": status,
+        "components": {
+            "postgres": postgres,
+            "inference": inference,
+            "domain": domain,
+            "disk": disk,
+        },
+    });
+    (status != Status::Error, report)
+}
+
+/// `503` while a dependency is in error, e.g. while the configured models are still being
+/// pulled after startup, so that Kubernetes stops routing traffic to the pod.
+async fn ready(
+    pool: web::Data<PgPool>,
+    host_pool: web::Data<HostPool>,
+    vlm_config: web::Data<config::Config>,
+    data_dir: web::Data<String>,
+    admin_config: web::Data<Config>,
+) -> impl Responder {
+    let (ready, report) = check_all(&pool, &host_pool, &vlm_config, &data_dir, &admin_config).await;
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+/// The same report as `/ready`, always with `200` so that it can be read while the server is not ready.
+async fn health_details(
+    pool: web::Data<PgPool>,
+    host_pool: web::Data<HostPool>,
+    vlm_config: web::Data<config::Config>,
+    data_dir: web::Data<String>,
+    admin_config: web::Data<Config>,
+) -> impl Responder {
+    let (_, report) = check_all(&pool, &host_pool, &vlm_config, &data_dir, &admin_config).await;
+    HttpResponse::Ok().json(report)
+}
+
+async fn list_models(
+    backend: web::Data<dyn InferenceBackend>,
+    vlm_config: web::Data<config::Config>,
+) -> impl Responder {
+    match backend.list_models().await {
+        Ok(models) => HttpResponse::Ok().json(json!({
+            "models": models,
+            "allowed_models": vlm_config.allowed_models,
+            "missing_models": missing_models(backend.get_ref(), &vlm_config).await,
+        })),
+        Err(e) => {
+            tracing::error!("Failed to list models: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to list models")
+        }
+    }
+}
+
+fn format_event(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+/// Pulls a model on every inference host, streaming Ollama's progress as Server-Sent Events.
+/// A `done` event closes the stream and tells which hosts have the model. Like
+/// [`HostPool::ensure_model`], the model only counts as available once every host has it.
+async fn pull_model(
+    host_pool: web::Data<HostPool>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let model = name.into_inner();
+    let progress = match host_pool.pull_model(&model).await {
+        Ok(progress) => progress,
+        Err(e) => {
+            tracing::error!("Failed to pull model {}: {:?}", model, e);
+            return HttpResponse::BadGateway().body(format!("Failed to pull model: {}", e));
+        }
+    };
+    let events = progress.map(|line| match line {
+        Ok(line) => format_event("progress", &json!(line)),
+        Err(e) => format_event("error", &json!({ "message": e.to_string() })),
+    });
+    let done = futures::stream::once(async move {
+        let hosts = host_pool.model_hosts(&model);
+        let available = hosts.iter().all(|(_, available)| *available);
+        let hosts: Vec<_> = hosts
+            .into_iter()
+            .map(|(host, available)| json!({ "host": host, "available": available }))
+            .collect();
+        format_event("done", &json!({ "model": model, "available": available, "hosts": hosts }))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events.chain(done).map(Ok::<_, actix_web::Error>))
+}
+
+async fn delete_model(
+    backend: web::Data<dyn InferenceBackend>,
+    vlm_config: web::Data<config::Config>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let model = name.into_inner();
+    if same_model(&model, &vlm_config.model) || same_model(&model, &vlm_config.llm_model) {
+        return HttpResponse::Conflict().body(format!("Model {} is configured as VLM_MODEL or LLM_MODEL", model));
+    }
+    match backend.delete_model(&model).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) if e.is::<ModelNotFound>() => HttpResponse::NotFound().body(e.to_string()),
+        Err(e) => {
+            tracing::error!("Failed to delete model {}: {:?}", model, e);
+            HttpResponse::BadGateway().body(format!("Failed to delete model: {}", e))
+        }
+    }
+}
+
+async fn prometheus_metrics(pool: web::Data<PgPool>) -> impl Responder {
+    match metrics::render(&pool).await {
+        Ok(body) => HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body),
+        Err(e) => {
+            tracing::error!("Failed to render metrics: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to render metrics")
+        }
+    }
+}
+
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    use actix_web::middleware::Logger;
+
+    cfg
+        .route("/health", web::get().to(|| async { "OK" }))
+        .route("/health/details", web::get().to(health_details))
+        .route("/ready", web::get().to(ready))
+        .route("/metrics", web::get().to(prometheus_metrics))
+        .service(
+            web::resource("/admin/models")
+                .wrap(Logger::default())
+                .route(web::get().to(list_models))
+        )
+        // Model names may contain slashes, e.g. `hf.co/org/model:tag`
+        .service(
+            web::resource("/admin/models/{name:.+}/pull")
+                .wrap(Logger::default())
+                .route(web::post().to(pull_model))
+        )
+        .service(
+            web::resource("/admin/models/{name:.+}")
+                .wrap(Logger::default())
+                .route(web::delete().to(delete_model))
+        );
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::{models::JobStatus, pg::{self, JobNotifier}, recording};
+
+/// Resolves once the job has been asked to stop, i.e. it is `cancelling` or already `cancelled`.
+/// Owners race this against their work and drop the work when it fires.
+pub async fn wait_for_cancel(pool: &PgPool, notifier: &JobNotifier, job_id: &str) {
+    // Subscribe before the first check, so that a cancel right after it is not missed
+    let mut events = notifier.subscribe();
+    loop {
+        match pg::get_job_by_id(pool, job_id).await {
+            Ok(Some(job)) => {
+                if matches!(job.common.status, JobStatus::Cancelling | JobStatus::Cancelled) {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => tracing::error!("Failed to poll job status: {:?}", e),
+        }
+        let notified = events
+            .wait_for(|n| n.id == job_id && matches!(n.status, JobStatus::Cancelling | JobStatus::Cancelled))
+            .await;
+        if notified {
+            return;
+        }
+    }
+}
+
+/// Removes everything the job left under `data_dir`, including the recording of a session.
+pub async fn cleanup_job_data(data_dir: &str, job_id: &str) {
+    for dir in [
+        format!("{}/input/{}", data_dir, job_id),
+        format!("{}/output/{}", data_dir, job_id),
+        recording::session_dir(data_dir, job_id),
+    ] {
+        match tokio::fs::remove_dir_all(&dir).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => tracing::warn!("Failed to delete folder {}: {:?}", dir, e),
+        }
+    }
+}
+
+/// Called by the owner of a job once it has stopped working on it.
+pub async fn finalize_cancel(pool: &PgPool, data_dir: &str, job_id: &str) {
+    match pg::mark_cancelled(pool, job_id).await {
+        Ok(Some(_)) => tracing::info!(job_id = %job_id, "Job cancelled"),
+        Ok(None) => (),
+        Err(e) => tracing::error!("Failed to mark job cancelled: {:?}", e),
+    }
+    cleanup_job_data(data_dir, job_id).await;
+}
+
+/// Periodically finalizes `cancelling` jobs whose owner never acknowledged the request,
+/// for example because the job was waiting for the uploader or its worker died.
+pub fn spawn_sweeper(pool: PgPool, data_dir: String, grace_period: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(grace_period);
+        loop {
+            interval.tick().await;
+            match pg::cancel_stale_jobs(&pool, grace_period).await {
+                Ok(jobs) => {
+                    for job in jobs {
+                        tracing::warn!(job_id = %job.common.id, "Force cancelled job after grace period");
+                        cleanup_job_data(&data_dir, &job.common.id).await;
+                    }
+                }
+                Err(e) => tracing::error!("Failed to cancel stale jobs: {:?}", e),
+            }
+        }
+    });
+}
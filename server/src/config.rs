@@ -1,18 +1,158 @@
+use std::str::FromStr;
+
 use serde::Deserialize;
 
+use crate::inference::same_model;
+
+/// Which API the inference host speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    Ollama,
+    /// Any server exposing `/v1/chat/completions`, e.g. vLLM, llama.cpp server or LM Studio.
+    OpenAi,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ollama" => Ok(BackendKind::Ollama),
+            "openai" => Ok(BackendKind::OpenAi),
+            _ => Err(format!("Unknown inference backend: {}", s)),
+        }
+    }
+}
+
+/// What a WebSocket session does with a new batch while `max_in_flight_batches` are running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for a running batch to finish, up to `max_queued_batches`.
+    Queue,
+    /// Abort the oldest running batch.
+    DropOldest,
+    /// Drop the new batch.
+    Reject,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(OverflowPolicy::Queue),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => Err(format!("Unknown overflow policy: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub model: String,
-    pub ollama_host: String,
+    pub llm_model: String,
+    pub allowed_models: Vec<String>,
+    pub backend: BackendKind,
+    pub inference_hosts: Vec<String>,
+    pub inference_api_key: Option<String>,
+    pub inference_health_interval: u64,
+    pub inference_connect_timeout: u64,
+    /// Seconds an inference request may go without receiving any data.
+    pub inference_read_timeout: u64,
+    pub inference_max_retries: u32,
+    pub inference_backoff_base: u64,
+    pub inference_backoff_max: u64,
     pub image_batch_size: usize,
+    pub max_in_flight_batches: usize,
+    pub max_queued_batches: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// Hash bits two images may differ in to count as near-duplicates, unset to keep every image.
+    pub dedup_threshold: Option<u32>,
+    /// Longest edge in pixels images are shrunk to before inference, 0 keeps their size.
+    pub image_max_edge: u32,
+    pub image_jpeg_quality: u8,
+    /// Times a response that does not match a job's `output_schema` is regenerated.
+    pub output_schema_retries: u32,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, std::env::VarError> {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let inference_hosts: Vec<String> = std::env::var("INFERENCE_HOSTS")
+            .or_else(|_| std::env::var("INFERENCE_HOST"))
+            .or_else(|_| std::env::var("OLLAMA_HOST"))?
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect();
+        if inference_hosts.is_empty() {
+            return Err("No inference host configured".into());
+        }
+        let model = std::env::var("VLM_MODEL")?;
+        let llm_model = std::env::var("LLM_MODEL").unwrap_or("llama3:latest".to_string());
+        // The default models are always allowed
+        let allowed = std::env::var("ALLOWED_MODELS").unwrap_or_default();
+        let mut allowed_models: Vec<String> = Vec::new();
+        for allowed in [model.as_str(), llm_model.as_str()].into_iter().chain(allowed.split(',').map(|m| m.trim())) {
+            if !allowed.is_empty() && !allowed_models.iter().any(|m| same_model(m, allowed)) {
+                allowed_models.push(allowed.to_string());
+            }
+        }
         Ok(Config {
-            model: std::env::var("VLM_MODEL")?,
-            ollama_host: std::env::var("OLLAMA_HOST")?,
-            image_batch_size: std::env::var("IMAGE_BATCH_SIZE").unwrap_or("5".to_string()).parse::<usize>().unwrap()
+            model,
+            llm_model,
+            allowed_models,
+            backend: std::env::var("INFERENCE_BACKEND").unwrap_or("ollama".to_string()).parse::<BackendKind>()?,
+            inference_hosts,
+            inference_api_key: std::env::var("INFERENCE_API_KEY").ok().filter(|key| !key.is_empty()),
+            inference_health_interval: std::env::var("INFERENCE_HEALTH_CHECK_INTERVAL").unwrap_or("10".to_string()).parse::<u64>()?,
+            inference_connect_timeout: std::env::var("INFERENCE_CONNECT_TIMEOUT").unwrap_or("5".to_string()).parse::<u64>()?,
+            inference_read_timeout: std::env::var("INFERENCE_READ_TIMEOUT").unwrap_or("300".to_string()).parse::<u64>()?,
+            inference_max_retries: std::env::var("INFERENCE_MAX_RETRIES").unwrap_or("3".to_string()).parse::<u32>()?,
+            inference_backoff_base: std::env::var("INFERENCE_BACKOFF_BASE_MS").unwrap_or("500".to_string()).parse::<u64>()?,
+            inference_backoff_max: std::env::var("INFERENCE_BACKOFF_MAX_MS").unwrap_or("10000".to_string()).parse::<u64>()?,
+            image_batch_size: std::env::var("IMAGE_BATCH_SIZE").unwrap_or("5".to_string()).parse::<usize>()?,
+            max_in_flight_batches: std::env::var("WS_MAX_IN_FLIGHT_BATCHES").unwrap_or("2".to_string()).parse::<usize>()?.max(1),
+            max_queued_batches: std::env::var("WS_MAX_QUEUED_BATCHES").unwrap_or("4".to_string()).parse::<usize>()?,
+            overflow_policy: std::env::var("WS_OVERFLOW_POLICY").unwrap_or("queue".to_string()).parse::<OverflowPolicy>()?,
+            dedup_threshold: std::env::var("DEDUP_THRESHOLD").ok().filter(|t| !t.is_empty()).map(|t| t.parse::<u32>()).transpose()?,
+            image_max_edge: std::env::var("IMAGE_MAX_EDGE").unwrap_or("1024".to_string()).parse::<u32>()?,
+            image_jpeg_quality: std::env::var("IMAGE_JPEG_QUALITY").unwrap_or("85".to_string()).parse::<u8>()?.clamp(1, 100),
+            output_schema_retries: std::env::var("OUTPUT_SCHEMA_MAX_RETRIES").unwrap_or("2".to_string()).parse::<u32>()?,
         })
     }
+
+    /// The defaults of [`Config::from_env`] with a single local Ollama host, for unit tests
+    /// that only care about a few fields.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Config {
+            model: "moondream:1.8b".to_string(),
+            llm_model: "llama3:latest".to_string(),
+            allowed_models: vec!["moondream:1.8b".to_string(), "llama3:latest".to_string()],
+            backend: BackendKind::Ollama,
+            inference_hosts: vec!["http://localhost:11434".to_string()],
+            inference_api_key: None,
+            inference_health_interval: 10,
+            inference_connect_timeout: 5,
+            inference_read_timeout: 300,
+            inference_max_retries: 3,
+            inference_backoff_base: 500,
+            inference_backoff_max: 10_000,
+            image_batch_size: 5,
+            max_in_flight_batches: 2,
+            max_queued_batches: 4,
+            overflow_policy: OverflowPolicy::Queue,
+            dedup_threshold: None,
+            image_max_edge: 1024,
+            image_jpeg_quality: 85,
+            output_schema_retries: 2,
+        }
+    }
+
+    pub fn is_model_allowed(&self, model: &str) -> bool {
+        self.allowed_models.iter().any(|m| same_model(m, model))
+    }
 }
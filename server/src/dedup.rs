@@ -0,0 +1,93 @@
+use image::DynamicImage;
+
+/// 64-bit difference hash: shrinks the image to 9x8 grayscale pixels and sets a bit for every
+/// pixel brighter than its right neighbour. Similar images differ in few bits.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.thumbnail_exact(9, 8).into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Drops images that look like the last image it let through. Comparing against the last kept
+/// image rather than the last seen one means a slowly changing scene still gets through.
+pub struct DuplicateFilter {
+    /// Largest number of differing hash bits for an image to count as a duplicate, `None` lets everything through.
+    threshold: Option<u32>,
+    last_kept: Option<u64>,
+    skipped: u64,
+}
+
+impl DuplicateFilter {
+    pub fn new(threshold: Option<u32>) -> Self {
+        DuplicateFilter {
+            threshold,
+            last_kept: None,
+            skipped: 0,
+        }
+    }
+
+    /// Number of images dropped so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Checks the [`dhash`] of an image against the last kept image, remembering it if it is kept.
+    pub fn is_duplicate(&mut self, hash: u64) -> bool {
+        let Some(threshold) = self.threshold else {
+            return false;
+        };
+        match self.last_kept {
+            Some(last) if (last ^ hash).count_ones() <= threshold => {
+                self.skipped += 1;
+                true
+            }
+            _ => {
+                self.last_kept = Some(hash);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_filter_keeps_everything() {
+        let mut filter = DuplicateFilter::new(None);
+        assert!(!filter.is_duplicate(0));
+        assert!(!filter.is_duplicate(0));
+        assert_eq!(filter.skipped(), 0);
+    }
+
+    #[test]
+    fn skips_images_within_threshold() {
+        let mut filter = DuplicateFilter::new(Some(2));
+        assert!(!filter.is_duplicate(0b0000));
+        assert!(filter.is_duplicate(0b0011));
+        assert!(!filter.is_duplicate(0b0111));
+        assert_eq!(filter.skipped(), 1);
+    }
+
+    #[test]
+    fn compares_against_last_kept_image() {
+        // Each image differs from the one before it by a single bit, but they drift away from
+        // the kept one until the third differs from it by more than the threshold
+        let mut filter = DuplicateFilter::new(Some(2));
+        assert!(!filter.is_duplicate(0b0000));
+        assert!(filter.is_duplicate(0b0001));
+        assert!(filter.is_duplicate(0b0011));
+        assert!(!filter.is_duplicate(0b0111));
+        assert!(filter.is_duplicate(0b0110));
+        assert_eq!(filter.skipped(), 3);
+    }
+}
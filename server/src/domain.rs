@@ -1,12 +1,73 @@
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
 
+use chrono::{DateTime, Utc};
 use futures::channel::mpsc::{self, Sender};
 use posemesh_domain_http::domain_data::{CreateDomainData, DomainData, UploadDomainData};
 use posemesh_domain_http::{domain_data::DownloadQuery, DomainClient};
 use tokio::fs::read_dir;
+use tokio::sync::watch;
 use tokio::{fs, spawn};
 use tokio::io::AsyncWriteExt;
 use futures::StreamExt;
+use serde::Serialize;
+
+use crate::metrics::METRICS;
+
+/// Outcome of the latest calls to the domain server. The `DomainClient` keeps its tokens to
+/// itself, so this is the closest we get to knowing whether it is still signed in.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DomainStatus {
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl DomainStatus {
+    /// Whether the latest call failed.
+    pub fn is_failing(&self) -> bool {
+        match (self.last_error_at, self.last_success_at) {
+            (Some(error_at), Some(success_at)) => error_at > success_at,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+static STATUS: Mutex<DomainStatus> = Mutex::new(DomainStatus {
+    last_success_at: None,
+    last_error_at: None,
+    last_error: None,
+});
+
+fn record_call<T>(res: &Result<T, Box<dyn std::error::Error + Send + Sync>>) {
+    let mut status = STATUS.lock().unwrap();
+    match res {
+        Ok(_) => status.last_success_at = Some(Utc::now()),
+        Err(e) => {
+            status.last_error_at = Some(Utc::now());
+            status.last_error = Some(e.to_string());
+        }
+    }
+}
+
+pub fn status() -> DomainStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DownloadProgress {
+    pub files: i64,
+    pub bytes: i64,
+}
+
+fn observe_transfer<T>(direction: &str, started: Instant, res: &Result<T, Box<dyn std::error::Error + Send + Sync>>) {
+    let result = if res.is_ok() { "ok" } else { "error" };
+    METRICS.domain_duration
+        .with_label_values(&[direction, result])
+        .observe(started.elapsed().as_secs_f64());
+}
 
 pub async fn download_for_job(
     domain_client: &DomainClient,
@@ -14,9 +75,26 @@ pub async fn download_for_job(
     domain_id: &str,
     data_dir: &str,
     query: &DownloadQuery,
-) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
-    let mut count = 0;
-    let mut rx = domain_client.download_domain_data(&domain_id, query).await?;
+    progress: &watch::Sender<DownloadProgress>,
+) -> Result<DownloadProgress, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let res = download_files(domain_client, job_id, domain_id, data_dir, query, progress).await;
+    observe_transfer("download", started, &res);
+    res
+}
+
+async fn download_files(
+    domain_client: &DomainClient,
+    job_id: &str,
+    domain_id: &str,
+    data_dir: &str,
+    query: &DownloadQuery,
+    progress: &watch::Sender<DownloadProgress>,
+) -> Result<DownloadProgress, Box<dyn std::error::Error + Send + Sync>> {
+    let mut count = DownloadProgress::default();
+    let rx = domain_client.download_domain_data(domain_id, query).await;
+    record_call(&rx);
+    let mut rx = rx?;
 
     while let Some(Ok(data)) = rx.next().await {
         let dir_path = format!("{}/input/{}", data_dir, job_id);
@@ -41,7 +119,10 @@ pub async fn download_for_job(
                 return Err(e.into());
             }
         }
-        count += 1;
+        count.files += 1;
+        count.bytes += data.data.len() as i64;
+        METRICS.domain_bytes.with_label_values(&["download"]).inc_by(data.data.len() as u64);
+        progress.send_replace(count);
     }
 
     Ok(count)
@@ -52,19 +133,22 @@ async fn upload_files(
     mut tx: Sender<UploadDomainData>
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     use futures::SinkExt;
-    let mut dir = read_dir(data_dir).await.expect("Failed to read data directory");
+    let mut dir = read_dir(data_dir).await?;
     while let Ok(Some(file)) = dir.next_entry().await {
         let file_path = file.path();
         let file_name = file_path.file_name().ok_or("Failed to get file name")?.to_str().ok_or("Failed to convert file name to string")?;
         let file_ext = file_path.extension().ok_or("Failed to get file extension")?.to_str().ok_or("Failed to convert file extension to string")?;
+        let data = fs::read(&file_path).await?;
+        let bytes = data.len() as u64;
         tx.send(UploadDomainData {
             create: Some(CreateDomainData {
                 name: file_name.to_string(),
                 data_type: file_ext.to_string(),
             }),
             update: None,
-            data: fs::read(file_path).await?
+            data,
         }).await?;
+        METRICS.domain_bytes.with_label_values(&["upload"]).inc_by(bytes);
     }
     tx.close().await?;
     Ok(())
@@ -75,12 +159,17 @@ pub async fn upload_for_job(
     domain_id: &str,
     data_dir: &str,
 ) -> Result<Vec<DomainData>, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
     let (tx, rx) = mpsc::channel::<UploadDomainData>(100);
 
     let data_dir = data_dir.to_string();
     spawn(async move {
-        upload_files(&data_dir, tx).await.expect("Failed to upload domain data");
+        if let Err(e) = upload_files(&data_dir, tx).await {
+            tracing::error!("Failed to upload domain data: {:?}", e);
+        }
     });
-    let res = domain_client.upload_domain_data(domain_id, rx).await?;
-    Ok(res)
+    let res = domain_client.upload_domain_data(domain_id, rx).await;
+    record_call(&res);
+    observe_transfer("upload", started, &res);
+    res
 }
@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use posemesh_domain_http::{domain_data::DownloadQuery, DomainClient};
+use sqlx::PgPool;
+use tokio::sync::watch;
+
+use crate::{cancel, domain::{download_for_job, DownloadProgress}, lease, models::JobError, pg::{self, JobNotifier}};
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Downloads the domain data of `downloading` jobs. Every download holds a lease on its job,
+/// so that the reaper fails downloads that were interrupted by a restart.
+#[derive(Clone)]
+pub struct Downloader {
+    id: String,
+    pool: PgPool,
+    notifier: JobNotifier,
+    domain_client: DomainClient,
+    data_dir: String,
+    lease: Duration,
+}
+
+impl Downloader {
+    pub fn new(
+        lease_config: &lease::Config,
+        pool: PgPool,
+        notifier: JobNotifier,
+        domain_client: DomainClient,
+        data_dir: String,
+    ) -> Self {
+        Downloader {
+            id: lease::worker_id("downloader"),
+            pool,
+            notifier,
+            domain_client,
+            data_dir,
+            lease: lease_config.duration(),
+        }
+    }
+
+    /// Downloads the domain data of a `downloading` job in the background, recording progress
+    /// as it goes, then moves the job to `pending`, or to `failed` with the download error.
+    pub fn spawn(&self, job_id: String, domain_id: String, query: DownloadQuery) {
+        let downloader = self.clone();
+        tokio::spawn(async move {
+            downloader.download(&job_id, &domain_id, &query).await;
+        });
+    }
+
+    async fn download(&self, job_id: &str, domain_id: &str, query: &DownloadQuery) {
+        let Downloader { id, pool, notifier, domain_client, data_dir, lease } = self;
+        tracing::info!(job_id = %job_id, "Downloading domain data");
+        match pg::claim_download(pool, job_id, id, *lease).await {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                tracing::warn!(job_id = %job_id, "Job is no longer downloading");
+                return;
+            }
+            Err(e) => {
+                // Left unclaimed, the reaper fails the job once it has been downloading for a lease
+                tracing::error!("Failed to claim download: {:?}", e);
+                return;
+            }
+        }
+
+        let (progress_tx, mut progress_rx) = watch::channel(DownloadProgress::default());
+        let download = download_for_job(domain_client, job_id, domain_id, data_dir, query, &progress_tx);
+        tokio::pin!(download);
+        let cancelled = cancel::wait_for_cancel(pool, notifier, job_id);
+        tokio::pin!(cancelled);
+        let lease_lost = lease::keep_alive(pool, job_id, id, *lease);
+        tokio::pin!(lease_lost);
+        let mut progress_interval = tokio::time::interval(PROGRESS_INTERVAL);
+
+        let result = loop {
+            tokio::select! {
+                result = &mut download => break result,
+                _ = &mut cancelled => {
+                    cancel::finalize_cancel(pool, data_dir, job_id).await;
+                    return;
+                }
+                _ = &mut lease_lost => {
+                    tracing::warn!(job_id = %job_id, "Abandoning download after losing its lease");
+                    return;
+                }
+                _ = progress_interval.tick() => {
+                    if !progress_rx.has_changed().unwrap_or(false) {
+                        continue;
+                    }
+                    let progress = *progress_rx.borrow_and_update();
+                    if let Err(e) = pg::update_download_progress(pool, job_id, progress.files, progress.bytes).await {
+                        tracing::error!("Failed to record download progress: {:?}", e);
+                    }
+                }
+            }
+        };
+
+        let err = match result {
+            Ok(progress) if progress.files > 0 => {
+                match pg::finish_download(pool, job_id, progress.files, progress.bytes).await {
+                    Ok(Some(_)) => tracing::info!(job_id = %job_id, files = progress.files, bytes = progress.bytes, "Domain data downloaded"),
+                    Ok(None) => tracing::warn!(job_id = %job_id, "Job is no longer downloading"),
+                    Err(e) => tracing::error!("Failed to finish download: {:?}", e),
+                }
+                return;
+            }
+            Ok(_) => JobError {
+                code: "no_data".to_string(),
+                message: "No data found".to_string(),
+            },
+            Err(e) => {
+                tracing::error!("Failed to download domain data: {:?}", e);
+                JobError {
+                    code: "download_failed".to_string(),
+                    message: e.to_string(),
+                }
+            }
+        };
+
+        cancel::cleanup_job_data(data_dir, job_id).await;
+        if let Err(e) = pg::fail_download(pool, job_id, &err).await {
+            tracing::error!("Failed to fail job: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse, Responder};
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::pg::{JobNotification, JobNotifier};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize, Debug)]
+pub struct JobEventsQuery {
+    pub job_id: Option<String>,
+    pub domain_id: Option<String>,
+    pub job_type: Option<String>,
+}
+
+impl JobEventsQuery {
+    fn matches(&self, notification: &JobNotification) -> bool {
+        self.job_id.as_ref().is_none_or(|id| *id == notification.id)
+            && self.domain_id.as_ref().is_none_or(|id| Some(id) == notification.domain_id.as_ref())
+            && self.job_type.as_ref().is_none_or(|job_type| *job_type == notification.job_type)
+    }
+}
+
+fn format_event(notification: &JobNotification) -> String {
+    let data = serde_json::to_string(notification).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", notification.event.as_str(), data)
+}
+
+/// Streams job change events as Server-Sent Events. A `lagged` event tells the client that
+/// events were dropped and it should refetch the jobs it cares about.
+pub async fn job_events(
+    notifier: web::Data<JobNotifier>,
+    query: web::Query<JobEventsQuery>,
+) -> impl Responder {
+    let filter = query.into_inner();
+    let subscription = notifier.subscribe();
+    let keep_alive = tokio::time::interval_at(tokio::time::Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
+
+    let stream = futures::stream::unfold((subscription, keep_alive, filter), |(mut subscription, mut keep_alive, filter)| async move {
+        loop {
+            let frame = tokio::select! {
+                res = subscription.recv() => match res {
+                    Ok(notification) if filter.matches(&notification) => format_event(&notification),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => format!("event: lagged\ndata: {{\"skipped\":{}}}\n\n", skipped),
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+            };
+            return Some((Ok::<_, actix_web::Error>(Bytes::from(frame)), (subscription, keep_alive, filter)));
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream)
+}
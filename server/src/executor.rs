@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::{cancel, config, lease, handlers::{JobContext, JobRegistry}, inference::{missing_models, InferenceBackend}, models::{Job, JobError, JobStatus}, pg::{self, JobNotifier}};
+
+const CLAIM_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+pub struct Config {
+    pub enabled: bool,
+    pub concurrency: usize,
+    pub cancel_grace_period: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config {
+            enabled: std::env::var("JOB_EXECUTOR_ENABLED").unwrap_or("true".to_string()).parse::<bool>()?,
+            concurrency: std::env::var("JOB_EXECUTOR_CONCURRENCY").unwrap_or("1".to_string()).parse::<usize>()?,
+            cancel_grace_period: std::env::var("JOB_CANCEL_GRACE_PERIOD").unwrap_or("60".to_string()).parse::<u64>()?,
+        };
+        // The cancel sweeper runs every grace period, which can't be zero
+        if config.cancel_grace_period == 0 {
+            return Err("JOB_CANCEL_GRACE_PERIOD must be at least 1 second".into());
+        }
+        Ok(config)
+    }
+}
+
+struct Worker {
+    id: String,
+    pool: PgPool,
+    registry: Arc<JobRegistry>,
+    backend: Arc<dyn InferenceBackend>,
+    vlm_config: config::Config,
+    data_dir: String,
+    notifier: JobNotifier,
+    lease: Duration,
+}
+
+/// Spawns `concurrency` workers that claim pending jobs and run them to completion.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn(
+    config: &Config,
+    lease_config: &lease::Config,
+    pool: PgPool,
+    notifier: JobNotifier,
+    registry: Arc<JobRegistry>,
+    backend: Arc<dyn InferenceBackend>,
+    vlm_config: config::Config,
+    data_dir: String,
+) {
+    for index in 0..config.concurrency {
+        let worker = Worker {
+            id: lease::worker_id(&format!("executor-{}", index)),
+            pool: pool.clone(),
+            registry: registry.clone(),
+            backend: backend.clone(),
+            vlm_config: vlm_config.clone(),
+            data_dir: data_dir.clone(),
+            notifier: notifier.clone(),
+            lease: lease_config.duration(),
+        };
+        tokio::spawn(async move {
+            tracing::info!(worker_id = %worker.id, "Job executor started");
+            let mut events = worker.notifier.subscribe();
+            let mut waiting_for_models = false;
+            loop {
+                // Jobs claimed before the models are pulled would fail for lack of a host
+                let missing = missing_models(worker.backend.as_ref(), &worker.vlm_config).await;
+                if !missing.is_empty() {
+                    if !waiting_for_models {
+                        tracing::info!(worker_id = %worker.id, "Waiting for models {} before claiming jobs", missing.join(", "));
+                        waiting_for_models = true;
+                    }
+                    tokio::time::sleep(CLAIM_RETRY_DELAY).await;
+                    continue;
+                }
+                waiting_for_models = false;
+                match pg::claim_next_job(&worker.pool, &worker.id, worker.lease).await {
+                    Ok(Some(job)) => worker.process_job(job).await,
+                    Ok(None) => {
+                        events.wait_for(|n| n.status == JobStatus::Pending).await;
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to claim job: {:?}", e);
+                        tokio::time::sleep(CLAIM_RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Worker {
+    async fn process_job(&self, job: Job) {
+        let pool = &self.pool;
+        let data_dir = &self.data_dir;
+        let job_id = job.common.id.clone();
+        tracing::info!(job_id = %job_id, job_type = %job.job_type, "Processing job");
+        let ctx = JobContext {
+            backend: self.backend.as_ref(),
+            vlm_config: &self.vlm_config,
+            input_dir: format!("{}/input/{}", data_dir, job_id),
+        };
+
+        let result = match self.registry.get(&job.job_type) {
+            Some(handler) => tokio::select! {
+                result = handler.execute(&ctx, &job) => result,
+                _ = cancel::wait_for_cancel(pool, &self.notifier, &job_id) => {
+                    cancel::finalize_cancel(pool, data_dir, &job_id).await;
+                    return;
+                }
+                _ = lease::keep_alive(pool, &job_id, &self.id, self.lease) => {
+                    tracing::warn!(job_id = %job_id, "Abandoning job after losing its lease");
+                    return;
+                }
+            },
+            None => Err(JobError {
+                code: "unknown_job_type".to_string(),
+                message: format!("Unknown job type: {}", job.job_type),
+            }),
+        };
+        let output = match result {
+            Ok(output) => output,
+            Err(err) => {
+                tracing::error!(job_id = %job_id, "Error processing job: {}", err.message);
+                if let Err(e) = pg::fail_job(pool, &job_id, &err, &job.common.updated_at).await {
+                    tracing::error!("Failed to fail job: {:?}", e);
+                }
+                return;
+            }
+        };
+
+        let job = match pg::finish_processing(pool, &job_id, &output, &job.common.updated_at).await {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                tracing::warn!(job_id = %job_id, "Job was modified while running, dropping output");
+                return;
+            }
+            Err(e) => {
+                tracing::error!("Failed to store job output: {:?}", e);
+                return;
+            }
+        };
+
+        // The webhook, if any, is queued by the database once the job is completed
+        match pg::complete_job(pool, &job_id, &job.common.updated_at).await {
+            Ok(Some(_)) => tracing::info!(job_id = %job_id, "Job completed"),
+            Ok(None) => tracing::warn!(job_id = %job_id, "Job was modified before it completed"),
+            Err(e) => tracing::error!("Failed to complete job: {:?}", e),
+        }
+    }
+}
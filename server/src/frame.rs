@@ -0,0 +1,89 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Starts a binary frame that wraps an image in an envelope: the magic, the length of the JSON
+/// header as a big-endian u32, the header, then the image bytes. Other frames are raw images.
+const ENVELOPE_MAGIC: &[u8; 4] = b"VLMF";
+const MAX_ENVELOPE_HEADER: usize = 16 * 1024;
+
+/// What the client knows about a frame, sent in the header of an envelope.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FrameMetadata {
+    /// When the camera took the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Sequence number of the frame on its device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// Splits a binary frame into its metadata and image bytes. Raw images have no metadata.
+pub fn parse_frame(bin: Bytes) -> Result<(FrameMetadata, Bytes), String> {
+    if !bin.starts_with(ENVELOPE_MAGIC) {
+        return Ok((FrameMetadata::default(), bin));
+    }
+    let Some(len) = bin.get(4..8) else {
+        return Err("Envelope is missing its header length".to_string());
+    };
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if len > MAX_ENVELOPE_HEADER {
+        return Err(format!("Envelope header is longer than {} bytes", MAX_ENVELOPE_HEADER));
+    }
+    let Some(header) = bin.get(8..8 + len) else {
+        return Err("Envelope is shorter than its header length".to_string());
+    };
+    let metadata = serde_json::from_slice::<FrameMetadata>(header).map_err(|e| format!("Invalid envelope header: {}", e))?;
+    let image = bin.slice(8 + len..);
+    if image.is_empty() {
+        return Err("Envelope has no image".to_string());
+    }
+    Ok((metadata, image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(header: &[u8], image: &[u8]) -> Bytes {
+        let mut frame = ENVELOPE_MAGIC.to_vec();
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend_from_slice(header);
+        frame.extend_from_slice(image);
+        Bytes::from(frame)
+    }
+
+    #[test]
+    fn raw_image_has_no_metadata() {
+        let (metadata, image) = parse_frame(Bytes::from_static(b"\xff\xd8\xff")).unwrap();
+        assert!(metadata.captured_at.is_none() && metadata.device_id.is_none() && metadata.seq.is_none());
+        assert_eq!(&image[..], b"\xff\xd8\xff");
+    }
+
+    #[test]
+    fn envelope_is_split_into_metadata_and_image() {
+        let header = br#"{"captured_at": "2025-10-01T12:00:00.250Z", "device_id": "glasses-1", "seq": 42}"#;
+        let (metadata, image) = parse_frame(envelope(header, b"\xff\xd8\xff")).unwrap();
+        assert_eq!(metadata.captured_at.unwrap().to_rfc3339(), "2025-10-01T12:00:00.250+00:00");
+        assert_eq!(metadata.device_id.as_deref(), Some("glasses-1"));
+        assert_eq!(metadata.seq, Some(42));
+        assert_eq!(&image[..], b"\xff\xd8\xff");
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        assert!(parse_frame(Bytes::from_static(b"VLMF\x00\x00")).is_err());
+        assert!(parse_frame(envelope(b"{}", b"")).is_err());
+        assert!(parse_frame(envelope(b"not json", b"\xff\xd8\xff")).is_err());
+
+        let mut truncated = envelope(b"{}", b"").to_vec();
+        truncated.truncate(9);
+        assert!(parse_frame(Bytes::from(truncated)).is_err());
+
+        let mut too_long = ENVELOPE_MAGIC.to_vec();
+        too_long.extend_from_slice(&(MAX_ENVELOPE_HEADER as u32 + 1).to_be_bytes());
+        assert!(parse_frame(Bytes::from(too_long)).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{config, inference::InferenceBackend, models::{Job, JobError}, pipelines::{self, OutputSchema, SchemaMismatch}, preprocess::InvalidImage};
+
+/// Everything a handler needs to execute a claimed job.
+pub struct JobContext<'a> {
+    pub backend: &'a dyn InferenceBackend,
+    pub vlm_config: &'a config::Config,
+    pub input_dir: String,
+}
+
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    /// The `job_type` this handler is registered under.
+    fn job_type(&self) -> &'static str;
+
+    /// JSON schema that `input` must satisfy for this job type.
+    fn input_schema(&self) -> serde_json::Value;
+
+    async fn execute(&self, ctx: &JobContext<'_>, job: &Job) -> Result<serde_json::Value, JobError>;
+}
+
+/// Structured rejection of a job request, returned as the 400 body.
+#[derive(Serialize, Debug)]
+pub struct InputError {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct JobTypeInfo {
+    pub job_type: &'static str,
+    pub input_schema: serde_json::Value,
+}
+
+struct RegisteredHandler {
+    handler: Arc<dyn JobHandler>,
+    validator: jsonschema::Validator,
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+    handlers: BTreeMap<&'static str, RegisteredHandler>,
+}
+
+impl JobRegistry {
+    pub fn with_default_handlers() -> Self {
+        let mut registry = JobRegistry::default();
+        registry.register(Arc::new(TaskTimingHandler));
+        registry.register(Arc::new(VlmOnlyHandler));
+        registry
+    }
+
+    /// Registers a handler, replacing any previous handler for the same job type.
+    /// Panics if the handler's input schema is not a valid JSON schema.
+    pub fn register(&mut self, handler: Arc<dyn JobHandler>) {
+        let validator = jsonschema::validator_for(&handler.input_schema())
+            .unwrap_or_else(|e| panic!("Invalid input schema for job type {}: {}", handler.job_type(), e));
+        self.handlers.insert(handler.job_type(), RegisteredHandler { handler, validator });
+    }
+
+    pub fn get(&self, job_type: &str) -> Option<Arc<dyn JobHandler>> {
+        self.handlers.get(job_type).map(|h| h.handler.clone())
+    }
+
+    pub fn list(&self) -> Vec<JobTypeInfo> {
+        self.handlers
+            .values()
+            .map(|h| JobTypeInfo {
+                job_type: h.handler.job_type(),
+                input_schema: h.handler.input_schema(),
+            })
+            .collect()
+    }
+
+    /// Checks that `job_type` is registered and `input` matches its schema.
+    pub fn validate(&self, job_type: &str, input: &serde_json::Value) -> Result<(), InputError> {
+        let Some(registered) = self.handlers.get(job_type) else {
+            return Err(InputError {
+                code: "unknown_job_type",
+                message: format!("Unknown job type: {}", job_type),
+                errors: vec![],
+            });
+        };
+        let errors: Vec<String> = registered
+            .validator
+            .iter_errors(input)
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect();
+        if !errors.is_empty() {
+            return Err(InputError {
+                code: "invalid_input",
+                message: format!("Input does not match the schema for {}", job_type),
+                errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the models picked in `input.vlm_model` and `input.llm_model` are in the allowlist.
+pub fn validate_models(vlm_config: &config::Config, input: &serde_json::Value) -> Result<(), InputError> {
+    let errors: Vec<String> = ["vlm_model", "llm_model"]
+        .into_iter()
+        .filter_map(|key| input.get(key).and_then(|model| model.as_str()).map(|model| (key, model)))
+        .filter(|(_, model)| !vlm_config.is_model_allowed(model))
+        .map(|(key, model)| format!("/{}: model {} is not allowed", key, model))
+        .collect();
+    if !errors.is_empty() {
+        return Err(InputError {
+            code: "model_not_allowed",
+            message: format!("Allowed models: {}", vlm_config.allowed_models.join(", ")),
+            errors,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `input.output_schema`, if set, is a valid JSON schema.
+pub fn validate_output_schema(input: &serde_json::Value) -> Result<(), InputError> {
+    let Some(schema) = input.get("output_schema") else {
+        return Ok(());
+    };
+    OutputSchema::compile(schema).map(|_| ()).map_err(|e| InputError {
+        code: "invalid_output_schema",
+        message: "output_schema is not a valid JSON schema".to_string(),
+        errors: vec![format!("/output_schema: {}", e)],
+    })
+}
+
+fn parse_input<T: serde::de::DeserializeOwned>(job: &Job) -> Result<T, JobError> {
+    serde_json::from_value(job.input.clone()).map_err(|e| JobError {
+        code: "invalid_input".to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn to_output<T: Serialize>(output: T) -> Result<serde_json::Value, JobError> {
+    serde_json::to_value(output).map_err(|e| JobError {
+        code: "internal_error".to_string(),
+        message: e.to_string(),
+    })
+}
+
+fn pipeline_error(e: Box<dyn std::error::Error + Send + Sync>) -> JobError {
+    let code = if e.is::<InvalidImage>() {
+        "invalid_image"
+    } else if e.is::<SchemaMismatch>() {
+        "output_schema_mismatch"
+    } else {
+        "inference_error"
+    };
+    JobError {
+        code: code.to_string(),
+        message: e.to_string(),
+    }
+}
+
+pub struct TaskTimingHandler;
+
+#[async_trait]
+impl JobHandler for TaskTimingHandler {
+    fn job_type(&self) -> &'static str {
+        "task_timing_v1"
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "required": ["vlm_prompt", "prompt"],
+            "properties": {
+                "vlm_prompt": { "type": "string", "minLength": 1 },
+                "prompt": { "type": "string", "minLength": 1 },
+                "vlm_model": { "type": "string", "minLength": 1 },
+                "llm_model": { "type": "string", "minLength": 1 },
+                "output_schema": { "type": "object" },
+                "webhook_url": { "type": "string" },
+                "webhook_secret": { "type": "string" }
+            }
+        })
+    }
+
+    async fn execute(&self, ctx: &JobContext<'_>, job: &Job) -> Result<serde_json::Value, JobError> {
+        let input: pipelines::TaskTimingInput = parse_input(job)?;
+        let image_paths = pipelines::load_images(&ctx.input_dir).await?;
+        let output = pipelines::run_task_timing(ctx.backend, ctx.vlm_config, &input, &image_paths)
+            .await
+            .map_err(pipeline_error)?;
+        to_output(output)
+    }
+}
+
+pub struct VlmOnlyHandler;
+
+#[async_trait]
+impl JobHandler for VlmOnlyHandler {
+    fn job_type(&self) -> &'static str {
+        "vlm_only"
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "required": ["vlm_prompt"],
+            "properties": {
+                "vlm_prompt": { "type": "string", "minLength": 1 },
+                "vlm_model": { "type": "string", "minLength": 1 },
+                "output_schema": { "type": "object" },
+                "webhook_url": { "type": "string" },
+                "webhook_secret": { "type": "string" }
+            }
+        })
+    }
+
+    async fn execute(&self, ctx: &JobContext<'_>, job: &Job) -> Result<serde_json::Value, JobError> {
+        let input: pipelines::VlmOnlyInput = parse_input(job)?;
+        let image_paths = pipelines::load_images(&ctx.input_dir).await?;
+        let output = pipelines::run_vlm_only(ctx.backend, ctx.vlm_config, &input, &image_paths)
+            .await
+            .map_err(pipeline_error)?;
+        to_output(output)
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::inference::{same_model, ChatMessage, ChunkStream, Error, GenerateRequest, InferenceBackend, ModelNotFound, PullStream};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request that never reached the host is safe to send to another one.
+fn is_unreachable(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            return err.is_connect();
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// The outcome of probing a host.
+#[derive(Serialize, Debug)]
+pub struct HostStatus {
+    pub url: String,
+    pub healthy: bool,
+    /// How long listing the host's models took.
+    pub latency_ms: u64,
+    pub models: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct Host {
+    url: String,
+    backend: Box<dyn InferenceBackend>,
+    healthy: AtomicBool,
+    outstanding: AtomicUsize,
+    models: RwLock<HashSet<String>>,
+}
+
+impl Host {
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn has_model(&self, model: &str) -> bool {
+        self.models.read().unwrap().iter().any(|m| same_model(m, model))
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        if was_healthy && !healthy {
+            tracing::warn!(host = %self.url, "Removing unhealthy inference host");
+        } else if !was_healthy && healthy {
+            tracing::info!(host = %self.url, "Inference host is healthy");
+        }
+    }
+
+    /// Refreshes the host's health and the models it has.
+    async fn probe(&self) -> HostStatus {
+        let started = Instant::now();
+        let res = tokio::time::timeout(PROBE_TIMEOUT, self.backend.list_models()).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let error = match res {
+            Ok(Ok(models)) => {
+                *self.models.write().unwrap() = models.into_iter().collect();
+                self.set_healthy(true);
+                None
+            }
+            Ok(Err(e)) => {
+                tracing::debug!(host = %self.url, "Inference host probe failed: {:?}", e);
+                self.set_healthy(false);
+                Some(e.to_string())
+            }
+            Err(_) => {
+                tracing::debug!(host = %self.url, "Inference host probe timed out");
+                self.set_healthy(false);
+                Some(format!("Timed out after {:?}", PROBE_TIMEOUT))
+            }
+        };
+        let mut models: Vec<String> = self.models.read().unwrap().iter().cloned().collect();
+        models.sort();
+        HostStatus {
+            url: self.url.clone(),
+            healthy: self.is_healthy(),
+            latency_ms,
+            models,
+            error,
+        }
+    }
+}
+
+/// Counts a request against its host until dropped.
+struct Outstanding(Arc<Host>);
+
+impl Outstanding {
+    fn new(host: Arc<Host>) -> Self {
+        host.outstanding.fetch_add(1, Ordering::Relaxed);
+        Outstanding(host)
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads requests over several inference hosts. Each request goes to the healthy host with
+/// the fewest requests in flight among those that have the requested model. Hosts are removed
+/// when a probe or a connection fails and added back once a probe succeeds.
+pub struct HostPool {
+    hosts: Vec<Arc<Host>>,
+}
+
+impl HostPool {
+    pub fn new(hosts: Vec<(String, Box<dyn InferenceBackend>)>) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(url, backend)| Arc::new(Host {
+                url,
+                backend,
+                // Hosts join the pool after their first successful probe
+                healthy: AtomicBool::new(false),
+                outstanding: AtomicUsize::new(0),
+                models: RwLock::new(HashSet::new()),
+            }))
+            .collect();
+        HostPool { hosts }
+    }
+
+    /// Probes every host every `interval`.
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                pool.probe_all().await;
+            }
+        });
+    }
+
+    /// Probes every host right away.
+    pub async fn probe_all(&self) -> Vec<HostStatus> {
+        futures::future::join_all(self.hosts.iter().map(|host| host.probe())).await
+    }
+
+    /// Whether each host has `model`, by host URL.
+    pub fn model_hosts(&self, model: &str) -> Vec<(String, bool)> {
+        self.hosts.iter().map(|host| (host.url.clone(), host.has_model(model))).collect()
+    }
+
+    fn pick(&self, model: &str, tried: &[usize]) -> Option<(usize, Arc<Host>)> {
+        self.hosts
+            .iter()
+            .enumerate()
+            .filter(|(index, host)| !tried.contains(index) && host.is_healthy() && host.has_model(model))
+            .min_by_key(|(_, host)| host.outstanding.load(Ordering::Relaxed))
+            .map(|(index, host)| (index, host.clone()))
+    }
+
+    /// Runs `call` on the best host for `model`, moving on to the next one when a host can't be reached.
+    async fn route<T, F, Fut>(&self, model: &str, call: F) -> Result<T, Error>
+    where
+        F: Fn(Arc<Host>, Outstanding) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut tried = Vec::new();
+        loop {
+            let Some((index, host)) = self.pick(model, &tried) else {
+                return Err(format!("No healthy inference host has model {}", model).into());
+            };
+            let outstanding = Outstanding::new(host.clone());
+            match call(host.clone(), outstanding).await {
+                Err(e) if is_unreachable(e.as_ref()) => {
+                    tracing::warn!(host = %host.url, "Inference host unreachable, trying another: {:?}", e);
+                    host.set_healthy(false);
+                    tried.push(index);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InferenceBackend for HostPool {
+    async fn generate(&self, request: GenerateRequest) -> Result<String, Error> {
+        self.route(&request.model, |host, outstanding| {
+            let request = request.clone();
+            async move {
+                let _outstanding = outstanding;
+                host.backend.generate(request).await
+            }
+        }).await
+    }
+
+    async fn generate_stream(&self, request: GenerateRequest) -> Result<ChunkStream, Error> {
+        self.route(&request.model, |host, outstanding| {
+            let request = request.clone();
+            async move {
+                let chunks = host.backend.generate_stream(request).await?;
+                // The request stays outstanding for as long as the response is streamed
+                Ok(chunks.map(move |chunk| {
+                    let _ = &outstanding;
+                    chunk
+                }).boxed())
+            }
+        }).await
+    }
+
+    async fn chat(&self, model: &str, messages: Vec<ChatMessage>, format: Option<&serde_json::Value>) -> Result<String, Error> {
+        self.route(model, |host, outstanding| {
+            let messages = messages.clone();
+            async move {
+                let _outstanding = outstanding;
+                host.backend.chat(model, messages, format).await
+            }
+        }).await
+    }
+
+    /// Models available on at least one healthy host.
+    async fn list_models(&self) -> Result<Vec<String>, Error> {
+        let mut models: Vec<String> = self.hosts
+            .iter()
+            .filter(|host| host.is_healthy())
+            .flat_map(|host| host.models.read().unwrap().iter().cloned().collect::<Vec<_>>())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        models.sort();
+        Ok(models)
+    }
+
+    /// Makes the model available on every host that does not have it yet. Fails while any host
+    /// is missing it, so that callers retry until the model is everywhere. Hosts that already
+    /// have it are left alone on retries.
+    async fn ensure_model(&self, model: &str) -> Result<(), Error> {
+        let missing: Vec<&Arc<Host>> = self.hosts.iter().filter(|host| !host.has_model(model)).collect();
+        let results = futures::future::join_all(missing.iter().map(|host| async move {
+            let res = host.backend.ensure_model(model).await;
+            match &res {
+                Ok(()) => {
+                    host.models.write().unwrap().insert(model.to_string());
+                    host.set_healthy(true);
+                }
+                Err(e) => tracing::error!(host = %host.url, "Failed to ensure model {}: {:?}", model, e),
+            }
+            res
+        })).await;
+        let failed: Vec<&str> = missing
+            .iter()
+            .zip(results)
+            .filter(|(_, res)| res.is_err())
+            .map(|(host, _)| host.url.as_str())
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(format!("Model {} is not available on {}", model, failed.join(", ")).into())
+    }
+
+    /// Pulls the model on every host at once, tagging each line of progress with its host.
+    /// Fails only if no host could start the pull.
+    async fn pull_model(&self, model: &str) -> Result<PullStream, Error> {
+        let started = futures::future::join_all(self.hosts.iter().map(|host| host.backend.pull_model(model))).await;
+        let mut streams = Vec::new();
+        let mut errors = Vec::new();
+        for (host, res) in self.hosts.iter().zip(started) {
+            match res {
+                Ok(progress) => {
+                    let host = host.clone();
+                    let model = model.to_string();
+                    streams.push(progress.map(move |line| match line {
+                        Ok(mut line) => {
+                            if line.status == "success" {
+                                host.models.write().unwrap().insert(model.clone());
+                                host.set_healthy(true);
+                            }
+                            line.host = Some(host.url.clone());
+                            Ok(line)
+                        }
+                        Err(e) => Err(format!("{}: {}", host.url, e).into()),
+                    }).boxed());
+                }
+                Err(e) => {
+                    tracing::error!(host = %host.url, "Failed to start pull of model {}: {:?}", model, e);
+                    errors.push(format!("{}: {}", host.url, e));
+                }
+            }
+        }
+        if streams.is_empty() {
+            return Err(format!("Failed to pull model {}: {}", model, errors.join("; ")).into());
+        }
+        let errors = futures::stream::iter(errors.into_iter().map(|e| Err(e.into())));
+        Ok(errors.chain(futures::stream::select_all(streams)).boxed())
+    }
+
+    /// Deletes the model from every host that has it. Succeeds if at least one host deleted it.
+    async fn delete_model(&self, model: &str) -> Result<(), Error> {
+        let results = futures::future::join_all(self.hosts.iter().map(|host| async move {
+            let res = host.backend.delete_model(model).await;
+            match &res {
+                Ok(()) => host.models.write().unwrap().retain(|m| !same_model(m, model)),
+                Err(e) if e.is::<ModelNotFound>() => (),
+                Err(e) => tracing::error!(host = %host.url, "Failed to delete model {}: {:?}", model, e),
+            }
+            res
+        })).await;
+        if results.iter().any(|res| res.is_ok()) {
+            return Ok(());
+        }
+        // Report a host that failed over the ones that don't have the model
+        let mut errors: Vec<Error> = results.into_iter().filter_map(Result::err).collect();
+        match errors.iter().position(|e| !e.is::<ModelNotFound>()) {
+            Some(index) => Err(errors.swap_remove(index)),
+            None => Err(ModelNotFound(model.to_string()).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{Notify, Semaphore};
+
+    use super::*;
+    use crate::inference::GenerateOptions;
+
+    /// Answers with its name once a permit is released, so that requests stay in flight.
+    struct BlockingBackend {
+        name: &'static str,
+        started: Arc<Notify>,
+        release: Arc<Semaphore>,
+    }
+
+    impl BlockingBackend {
+        async fn answer(&self) -> Result<String, Error> {
+            self.started.notify_one();
+            let _permit = self.release.acquire().await?;
+            Ok(self.name.to_string())
+        }
+    }
+
+    #[async_trait]
+    impl InferenceBackend for BlockingBackend {
+        async fn generate(&self, _request: GenerateRequest) -> Result<String, Error> {
+            self.answer().await
+        }
+
+        async fn generate_stream(&self, _request: GenerateRequest) -> Result<ChunkStream, Error> {
+            unimplemented!()
+        }
+
+        async fn chat(&self, _model: &str, _messages: Vec<ChatMessage>, _format: Option<&serde_json::Value>) -> Result<String, Error> {
+            self.answer().await
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>, Error> {
+            Ok(vec!["llava:latest".to_string()])
+        }
+
+        async fn ensure_model(&self, _model: &str) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn pull_model(&self, _model: &str) -> Result<PullStream, Error> {
+            unimplemented!()
+        }
+
+        async fn delete_model(&self, _model: &str) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    async fn blocking_pool() -> (Arc<HostPool>, Arc<Notify>, Arc<Semaphore>) {
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Semaphore::new(0));
+        let hosts = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let backend: Box<dyn InferenceBackend> = Box::new(BlockingBackend {
+                    name,
+                    started: started.clone(),
+                    release: release.clone(),
+                });
+                (name.to_string(), backend)
+            })
+            .collect();
+        let pool = Arc::new(HostPool::new(hosts));
+        pool.probe_all().await;
+        (pool, started, release)
+    }
+
+    fn request() -> GenerateRequest {
+        GenerateRequest {
+            model: "llava".to_string(),
+            prompt: "Describe the image".to_string(),
+            images: Vec::new(),
+            options: GenerateOptions::default(),
+            format: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_goes_to_idle_host_while_another_is_busy() {
+        let (pool, started, release) = blocking_pool().await;
+
+        let busy = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.generate(request()).await.unwrap() }
+        });
+        started.notified().await;
+        let idle = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.generate(request()).await.unwrap() }
+        });
+        started.notified().await;
+
+        release.add_permits(2);
+        assert_eq!(busy.await.unwrap(), "a");
+        assert_eq!(idle.await.unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn chat_goes_to_idle_host_while_another_is_busy() {
+        let (pool, started, release) = blocking_pool().await;
+
+        let busy = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.chat("llava", Vec::new(), None).await.unwrap() }
+        });
+        started.notified().await;
+        let idle = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.chat("llava", Vec::new(), None).await.unwrap() }
+        });
+        started.notified().await;
+
+        release.add_permits(2);
+        assert_eq!(busy.await.unwrap(), "a");
+        assert_eq!(idle.await.unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn finished_requests_stop_counting_against_their_host() {
+        let (pool, _started, release) = blocking_pool().await;
+
+        release.add_permits(2);
+        assert_eq!(pool.generate(request()).await.unwrap(), "a");
+        assert_eq!(pool.generate(request()).await.unwrap(), "a");
+        assert!(pool.hosts.iter().all(|host| host.outstanding.load(Ordering::Relaxed) == 0));
+    }
+}
@@ -1,42 +1,41 @@
 use actix_web::{web, HttpResponse, Responder};
-use posemesh_domain_http::{domain_data::DownloadQuery, DomainClient};
+use posemesh_domain_http::domain_data::DownloadQuery;
 use uuid::Uuid;
 
-use crate::{models::{CreateJobRequest, ListJobsRequest, RetryJobRequest}, stream::ws_index};
+use crate::{config, downloader::Downloader, events::job_events, handlers::{validate_models, validate_output_schema, JobRegistry}, models::{CreateJobRequest, JobStatus, ListJobsRequest, RetryJobRequest}, stream::ws_index, webhook};
 
 async fn create_job(
     pool: web::Data<sqlx::PgPool>,
-    domain_client: web::Data<DomainClient>,
-    data_dir: web::Data<String>,
+    downloader: web::Data<Downloader>,
+    registry: web::Data<JobRegistry>,
+    vlm_config: web::Data<config::Config>,
     job: web::Json<CreateJobRequest>,
 ) -> impl Responder {
-    let id = Uuid::new_v4().to_string();
-    let res = serde_json::from_value::<DownloadQuery>(job.query.clone());
-    if let Err(e) = res {
-        tracing::error!("Failed to parse query: {:?}", e);
-        return HttpResponse::BadRequest().body("Failed to parse query");
+    let validated = registry
+        .validate(&job.job_type, &job.input)
+        .and_then(|_| validate_models(&vlm_config, &job.input))
+        .and_then(|_| validate_output_schema(&job.input));
+    if let Err(e) = validated {
+        return HttpResponse::BadRequest().json(e);
     }
-    let query = res.unwrap();
-    let count = crate::domain::download_for_job(&domain_client, &id, &job.domain_id, &data_dir, &query).await;
-    if let Err(e) = count {
-        tracing::error!("Failed to download domain data: {:?}", e);
-        // Attempt to delete the input folder for this job
-        let input_dir = format!("{}/input/{}", &*data_dir, &id);
-        if let Err(e) = tokio::fs::remove_dir_all(&input_dir).await {
-            tracing::warn!("Failed to delete input folder {}: {:?}", input_dir, e);
+    let id = Uuid::new_v4().to_string();
+    let query = match serde_json::from_value::<DownloadQuery>(job.query.clone()) {
+        Ok(query) => query,
+        Err(e) => {
+            tracing::error!("Failed to parse query: {:?}", e);
+            return HttpResponse::BadRequest().body("Failed to parse query");
         }
-        return HttpResponse::InternalServerError().body("Failed to download domain data");
-    }
-    if count.unwrap() == 0 {
-        return HttpResponse::BadRequest().body("No data found");
-    }
+    };
 
-    let res = crate::pg::create_job(&pool, &id, &job.domain_id, &job.query, &job.input, &job.job_type).await;
+    let mut input = job.input.clone();
+    let webhook_secret = webhook::take_secret(&mut input);
+    let res = crate::pg::create_job(&pool, &id, &job.domain_id, &job.query, &input, webhook_secret.as_deref(), &job.job_type, &JobStatus::Downloading).await;
     if let Err(e) = res {
         tracing::error!("Failed to create job: {:?}", e);
         return HttpResponse::InternalServerError().body("Failed to create job");
     }
     let job_schema = res.unwrap();
+    downloader.spawn(id, job.domain_id.clone(), query);
     HttpResponse::Ok().json(job_schema)
 }
 
@@ -68,8 +67,56 @@ async fn get_job(
     }
 }
 
+async fn list_job_attempts(
+    pool: web::Data<sqlx::PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let job_id = path.into_inner();
+    match crate::pg::get_job_by_id(&pool, &job_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return HttpResponse::NotFound().body("Job not found"),
+        Err(e) => {
+            tracing::error!("Failed to get job: {:?}", e);
+            return HttpResponse::InternalServerError().body("Failed to get job");
+        }
+    }
+    match crate::pg::list_job_attempts(&pool, &job_id).await {
+        Ok(attempts) => HttpResponse::Ok().json(attempts),
+        Err(e) => {
+            tracing::error!("Failed to list job attempts: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to list job attempts")
+        }
+    }
+}
+
+async fn list_webhook_deliveries(
+    pool: web::Data<sqlx::PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let job_id = path.into_inner();
+    match crate::pg::get_job_by_id(&pool, &job_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return HttpResponse::NotFound().body("Job not found"),
+        Err(e) => {
+            tracing::error!("Failed to get job: {:?}", e);
+            return HttpResponse::InternalServerError().body("Failed to get job");
+        }
+    }
+    match crate::pg::list_webhook_deliveries(&pool, &job_id).await {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => {
+            tracing::error!("Failed to list webhook deliveries: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to list webhook deliveries")
+        }
+    }
+}
+
 async fn retry_job(
     pool: web::Data<sqlx::PgPool>,
+    downloader: web::Data<Downloader>,
+    data_dir: web::Data<String>,
+    registry: web::Data<JobRegistry>,
+    vlm_config: web::Data<config::Config>,
     path: web::Path<String>,
     body: web::Json<RetryJobRequest>,
 ) -> impl Responder {
@@ -86,15 +133,42 @@ async fn retry_job(
     if body.job_type != job.job_type {
         return HttpResponse::BadRequest().body("Job type mismatch");
     }
+    let validated = registry
+        .validate(&body.job_type, &body.input)
+        .and_then(|_| validate_models(&vlm_config, &body.input))
+        .and_then(|_| validate_output_schema(&body.input));
+    if let Err(e) = validated {
+        return HttpResponse::BadRequest().json(e);
+    }
 
     // Only allow retry if job is Failed or Cancelled
-    use crate::models::JobStatus;
     match job.common.status {
         JobStatus::Failed | JobStatus::Cancelled | JobStatus::Completed => {
-            // Set job status to Pending, clear error and output
-            let res = crate::pg::retry_job(&pool, &job_id, &JobStatus::Pending, &body.input, &job.common.updated_at).await;
+            // Domain data is deleted when a job is cancelled or fails to download, fetch it again
+            let input_dir = format!("{}/input/{}", &*data_dir, &job_id);
+            let download_query = if tokio::fs::try_exists(&input_dir).await.unwrap_or(false) {
+                None
+            } else {
+                match serde_json::from_value::<DownloadQuery>(job.common.query.clone()) {
+                    Ok(query) => Some(query),
+                    Err(e) => {
+                        tracing::error!("Failed to parse query: {:?}", e);
+                        return HttpResponse::BadRequest().body("Failed to parse query");
+                    }
+                }
+            };
+            let status = if download_query.is_some() { JobStatus::Downloading } else { JobStatus::Pending };
+            // Reset the job status, clear error and output
+            let mut input = body.input.clone();
+            let webhook_secret = webhook::take_secret(&mut input);
+            let res = crate::pg::retry_job(&pool, &job_id, &status, &input, webhook_secret.as_deref(), &job.common.updated_at).await;
             match res {
-                Ok(_) => (),
+                Ok(Some(_)) => {
+                    if let Some(query) = download_query {
+                        downloader.spawn(job_id.clone(), job.common.domain_id.clone().unwrap_or_default(), query);
+                    }
+                }
+                Ok(None) => return HttpResponse::Conflict().body("Job was modified, please try again"),
                 Err(e) => {
                     tracing::error!("Failed to update job status: {:?}", e);
                     return HttpResponse::InternalServerError().body("Failed to update job status");
@@ -105,13 +179,55 @@ async fn retry_job(
     }
 
     match crate::pg::get_job_by_id(&pool, &job_id).await {
-        Ok(Some(job)) => return HttpResponse::Ok().json(job),
+        Ok(Some(job)) => HttpResponse::Ok().json(job),
+        Ok(None) => HttpResponse::NotFound().body("Job not found"),
+        Err(e) => {
+            tracing::error!("Failed to get job: {:?}", e);
+            HttpResponse::InternalServerError().body("Failed to get job")
+        }
+    }
+}
+
+async fn cancel_job(
+    pool: web::Data<sqlx::PgPool>,
+    data_dir: web::Data<String>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let job_id = path.into_inner();
+    let job = match crate::pg::get_job_by_id(&pool, &job_id).await {
+        Ok(Some(job)) => job,
         Ok(None) => return HttpResponse::NotFound().body("Job not found"),
         Err(e) => {
             tracing::error!("Failed to get job: {:?}", e);
             return HttpResponse::InternalServerError().body("Failed to get job");
         }
+    };
+
+    // Pending jobs have no owner and can be cancelled right away, downloading, running and uploading
+    // jobs are flagged so that their owner stops and finalizes the cancellation.
+    let status = match job.common.status {
+        JobStatus::Pending => JobStatus::Cancelled,
+        JobStatus::Downloading | JobStatus::Running | JobStatus::Uploading => JobStatus::Cancelling,
+        _ => return HttpResponse::BadRequest().body("Only downloading, pending, running or uploading jobs can be cancelled"),
+    };
+    let job = match crate::pg::set_job_status(&pool, &job_id, &status, &job.common.updated_at).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return HttpResponse::Conflict().body("Job was modified, please try again"),
+        Err(e) => {
+            tracing::error!("Failed to update job status: {:?}", e);
+            return HttpResponse::InternalServerError().body("Failed to update job status");
+        }
+    };
+    if let JobStatus::Cancelled = job.common.status {
+        crate::cancel::cleanup_job_data(&data_dir, &job_id).await;
     }
+    HttpResponse::Ok().json(job)
+}
+
+async fn list_job_types(
+    registry: web::Data<JobRegistry>,
+) -> impl Responder {
+    HttpResponse::Ok().json(registry.list())
 }
 
 pub fn app_config(cfg: &mut web::ServiceConfig) {
@@ -124,12 +240,37 @@ pub fn app_config(cfg: &mut web::ServiceConfig) {
                 .route(web::post().to(create_job))
                 .route(web::get().to(list_jobs))
         )
+        .service(
+            web::resource("/api/v1/jobs/events")
+                .wrap(Logger::default())
+                .route(web::get().to(job_events))
+        )
         .service(
             web::resource("/api/v1/jobs/{id}")
                 .wrap(Logger::default())
                 .route(web::get().to(get_job))
                 .route(web::put().to(retry_job))
         )
+        .service(
+            web::resource("/api/v1/jobs/{id}/attempts")
+                .wrap(Logger::default())
+                .route(web::get().to(list_job_attempts))
+        )
+        .service(
+            web::resource("/api/v1/jobs/{id}/webhooks")
+                .wrap(Logger::default())
+                .route(web::get().to(list_webhook_deliveries))
+        )
+        .service(
+            web::resource("/api/v1/jobs/{id}/cancel")
+                .wrap(Logger::default())
+                .route(web::post().to(cancel_job))
+        )
+        .service(
+            web::resource("/api/v1/job-types")
+                .wrap(Logger::default())
+                .route(web::get().to(list_job_types))
+        )
         .service(
             web::resource("/api/v1/ws")
                 .wrap(Logger::default())
@@ -0,0 +1,386 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::{config::{self, BackendKind}, host_pool::HostPool, ollama_client::OllamaClient, openai_client::OpenAiClient};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const ENSURE_MODELS_RETRY_MIN: Duration = Duration::from_secs(5);
+const ENSURE_MODELS_RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// Generated text as it is produced, ending with a chunk where `done` is set.
+pub type ChunkStream = BoxStream<'static, Result<GenerateChunk, Error>>;
+
+/// Progress of a model pull, ending with a `success` status.
+pub type PullStream = BoxStream<'static, Result<PullProgress, Error>>;
+
+/// Sampling options, serialized as Ollama's `options` object.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GenerateOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    pub images: Vec<Vec<u8>>,
+    pub options: GenerateOptions,
+    /// `"json"` for any JSON object, or a JSON schema the response must match.
+    pub format: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    pub images: Vec<Vec<u8>>,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "user".to_string(),
+            content: content.into(),
+            images: vec![],
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: content.into(),
+            images: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct GenerateChunk {
+    pub response: String,
+    pub done: bool,
+}
+
+/// One line of Ollama's pull progress. `completed` and `total` are bytes of the layer named by `digest`.
+#[derive(Serialize, Debug, Clone)]
+pub struct PullProgress {
+    /// The inference host pulling the model, set by the host pool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// The backend does not have the model.
+#[derive(Debug)]
+pub struct ModelNotFound(pub String);
+
+impl fmt::Display for ModelNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model {} not found", self.0)
+    }
+}
+
+impl std::error::Error for ModelNotFound {}
+
+/// A server that runs the VLM and LLM models.
+#[async_trait]
+pub trait InferenceBackend: Send + Sync {
+    /// Runs a single prompt, with optional images, and returns the full response.
+    async fn generate(&self, request: GenerateRequest) -> Result<String, Error>;
+
+    /// Like [`InferenceBackend::generate`], but streams the response as it is produced.
+    /// Dropping the stream aborts the request.
+    async fn generate_stream(&self, request: GenerateRequest) -> Result<ChunkStream, Error>;
+
+    /// Continues a conversation, `format` works like [`GenerateRequest::format`].
+    async fn chat(&self, model: &str, messages: Vec<ChatMessage>, format: Option<&serde_json::Value>) -> Result<String, Error>;
+
+    async fn list_models(&self) -> Result<Vec<String>, Error>;
+
+    /// Makes sure `model` can be served, pulling it if the backend supports it.
+    async fn ensure_model(&self, model: &str) -> Result<(), Error>;
+
+    /// Starts pulling `model` and streams its progress.
+    async fn pull_model(&self, model: &str) -> Result<PullStream, Error>;
+
+    /// Removes `model`, failing with [`ModelNotFound`] if the backend does not have it.
+    async fn delete_model(&self, model: &str) -> Result<(), Error>;
+}
+
+/// Builds the HTTP client a backend keeps for all its requests. There is no overall timeout
+/// because generations are streamed for as long as the model produces tokens.
+pub fn http_client(config: &config::Config) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.inference_connect_timeout))
+        .read_timeout(Duration::from_secs(config.inference_read_timeout))
+        .build()
+        .expect("Failed to build inference HTTP client")
+}
+
+/// Retries idempotent requests, such as listing or pulling models, that failed before the
+/// server could answer or with a status that says the server is overloaded or restarting.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    backoff_base: u64,
+    backoff_max: u64,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &config::Config) -> Self {
+        RetryPolicy {
+            max_retries: config.inference_max_retries,
+            backoff_base: config.inference_backoff_base,
+            backoff_max: config.inference_backoff_max,
+        }
+    }
+
+    /// Random delay up to the doubled backoff, so that clients failing together retry apart.
+    fn backoff(&self, retry: u32) -> Duration {
+        let cap = self.backoff_base.saturating_mul(2_u64.saturating_pow(retry)).min(self.backoff_max);
+        Duration::from_millis(rand::random_range(0..=cap))
+    }
+
+    fn is_retryable(err: &reqwest::Error) -> bool {
+        match err.status() {
+            Some(status) => status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            None => err.is_connect() || err.is_timeout(),
+        }
+    }
+
+    pub async fn run<T, F, Fut>(&self, what: &str, mut request: F) -> Result<T, reqwest::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, reqwest::Error>>,
+    {
+        let mut retry = 0;
+        loop {
+            match request().await {
+                Err(e) if retry < self.max_retries && Self::is_retryable(&e) => {
+                    let delay = self.backoff(retry);
+                    retry += 1;
+                    tracing::warn!(retry, "Failed to {}, retrying in {:?}: {:?}", what, delay, e);
+                    tokio::time::sleep(delay).await;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Builds a client for every configured host and pools them.
+pub fn from_config(config: &config::Config) -> Arc<HostPool> {
+    let hosts = config.inference_hosts
+        .iter()
+        .map(|host| {
+            let backend: Box<dyn InferenceBackend> = match config.backend {
+                BackendKind::Ollama => Box::new(OllamaClient::new(host, config)),
+                BackendKind::OpenAi => Box::new(OpenAiClient::new(host, config)),
+            };
+            (host.clone(), backend)
+        })
+        .collect();
+    Arc::new(HostPool::new(hosts))
+}
+
+/// Ensures every model in the background, retrying the ones that fail with a growing delay,
+/// so that the server starts while the models are still being pulled. With a [`HostPool`]
+/// a model is retried until every host has it.
+pub fn spawn_ensure_models(backend: Arc<dyn InferenceBackend>, models: Vec<String>) {
+    tokio::spawn(async move {
+        let mut delay = ENSURE_MODELS_RETRY_MIN;
+        let mut missing = models;
+        loop {
+            let mut failed = Vec::new();
+            for model in missing {
+                if let Err(e) = backend.ensure_model(&model).await {
+                    tracing::error!("Failed to ensure model {}, retrying in {:?}: {:?}", model, delay, e);
+                    failed.push(model);
+                }
+            }
+            if failed.is_empty() {
+                return;
+            }
+            missing = failed;
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(ENSURE_MODELS_RETRY_MAX);
+        }
+    });
+}
+
+/// The configured models the server can't work without, that no healthy inference host has.
+pub async fn missing_models(backend: &dyn InferenceBackend, vlm_config: &config::Config) -> Vec<String> {
+    let models = backend.list_models().await.unwrap_or_default();
+    [&vlm_config.model, &vlm_config.llm_model]
+        .into_iter()
+        .filter(|required| !models.iter().any(|m| same_model(m, required)))
+        .cloned()
+        .collect()
+}
+
+/// Whether two model names refer to the same model. A name without a tag means its `latest` tag.
+pub fn same_model(a: &str, b: &str) -> bool {
+    fn with_tag(name: &str) -> String {
+        match name.rsplit('/').next() {
+            Some(last) if last.contains(':') => name.to_string(),
+            _ => format!("{}:latest", name),
+        }
+    }
+    a == b || with_tag(a) == with_tag(b)
+}
+
+/// Guesses the MIME type of an image from its magic bytes, defaulting to JPEG.
+pub fn image_mime_type(image: &[u8]) -> &'static str {
+    if image.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if image.len() >= 12 && &image[0..4] == b"RIFF" && &image[8..12] == b"WEBP" {
+        "image/webp"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Splits a byte stream, such as NDJSON, into lines, holding on to a partial line until the
+/// rest of it arrives.
+#[derive(Default)]
+pub struct LineDecoder {
+    buffer: Vec<u8>,
+}
+
+impl LineDecoder {
+    /// Appends `chunk` and returns every line it completed, without line endings. Blank lines are skipped.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = line.trim_ascii();
+            if !line.is_empty() {
+                lines.push(line.to_vec());
+            }
+        }
+        lines
+    }
+
+    /// Returns the last line once the stream ended, if it was not terminated by a newline.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let line = std::mem::take(&mut self.buffer);
+        let line = line.trim_ascii();
+        (!line.is_empty()).then(|| line.to_vec())
+    }
+}
+
+/// Waits for a streamed response to finish and returns all of it.
+pub async fn collect(mut chunks: ChunkStream) -> Result<String, Error> {
+    let mut response = String::new();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        response.push_str(&chunk.response);
+        if chunk.done {
+            break;
+        }
+    }
+    Ok(response)
+}
+
+/// Turns a streamed, line delimited response into items, such as [`GenerateChunk`]s. `parse_line`
+/// returns `None` for lines that carry no item.
+pub fn decode_lines<T: Send + 'static>(
+    resp: reqwest::Response,
+    parse_line: fn(&[u8]) -> Result<Option<T>, Error>,
+) -> BoxStream<'static, Result<T, Error>> {
+    let mut decoder = LineDecoder::default();
+    // `None` marks the end of the body, where a last line without a newline may be left
+    resp.bytes_stream()
+        .map(Some)
+        .chain(futures::stream::once(async { None }))
+        .map(move |chunk| {
+            let lines = match chunk {
+                Some(Ok(chunk)) => decoder.push(&chunk),
+                Some(Err(e)) => return vec![Err(e.into())],
+                None => decoder.finish().into_iter().collect(),
+            };
+            lines
+                .iter()
+                .filter_map(|line| parse_line(line).transpose())
+                .collect::<Vec<_>>()
+        })
+        .flat_map(futures::stream::iter)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_decoder_joins_lines_split_across_chunks() {
+        let mut decoder = LineDecoder::default();
+        assert!(decoder.push(b"{\"response\":\"he").is_empty());
+        assert_eq!(decoder.push(b"llo\"}\n{\"done\":"), vec![b"{\"response\":\"hello\"}".to_vec()]);
+        assert_eq!(decoder.push(b"true}\r\n\n"), vec![b"{\"done\":true}".to_vec()]);
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn line_decoder_returns_trailing_line_without_newline() {
+        let mut decoder = LineDecoder::default();
+        assert_eq!(decoder.push(b"{\"a\":1}\n{\"b\":2}"), vec![b"{\"a\":1}".to_vec()]);
+        assert_eq!(decoder.finish(), Some(b"{\"b\":2}".to_vec()));
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn same_model_treats_missing_tag_as_latest() {
+        assert!(same_model("llama3", "llama3:latest"));
+        assert!(same_model("llama3:latest", "llama3"));
+        assert!(same_model("moondream:1.8b", "moondream:1.8b"));
+        assert!(!same_model("moondream:1.8b", "moondream"));
+        assert!(!same_model("llama3:8b", "llama3:70b"));
+    }
+
+    #[test]
+    fn same_model_reads_the_tag_after_the_last_slash() {
+        assert!(same_model("hf.co/org/model", "hf.co/org/model:latest"));
+        assert!(same_model("localhost:5000/model", "localhost:5000/model:latest"));
+        assert!(!same_model("hf.co/org/model:q4", "hf.co/org/model"));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_backoff_max() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            backoff_base: 100,
+            backoff_max: 1_000,
+        };
+        for (retry, cap) in [(0, 100), (1, 200), (3, 800), (4, 1_000), (20, 1_000), (u32::MAX, 1_000)] {
+            for _ in 0..100 {
+                assert!(policy.backoff(retry) <= Duration::from_millis(cap));
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::pg;
+
+pub struct Config {
+    pub duration: u64,
+    pub max_attempts: i32,
+    pub reaper_interval: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config {
+            duration: std::env::var("JOB_LEASE_DURATION").unwrap_or("60".to_string()).parse::<u64>()?,
+            max_attempts: std::env::var("JOB_MAX_ATTEMPTS").unwrap_or("3".to_string()).parse::<i32>()?,
+            reaper_interval: std::env::var("JOB_REAPER_INTERVAL").unwrap_or("30".to_string()).parse::<u64>()?,
+        };
+        // Heartbeats and the reaper run on intervals derived from these, which can't be zero
+        if config.duration == 0 {
+            return Err("JOB_LEASE_DURATION must be at least 1 second".into());
+        }
+        if config.reaper_interval == 0 {
+            return Err("JOB_REAPER_INTERVAL must be at least 1 second".into());
+        }
+        Ok(config)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration)
+    }
+}
+
+/// Identifies this process in `claimed_by`, suffixed with the name of the loop holding the claim.
+pub fn worker_id(name: &str) -> String {
+    let host = hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}:{}:{}", host, std::process::id(), name)
+}
+
+/// Extends the lease on a claimed job every third of its duration.
+/// Resolves only when the lease is lost, i.e. the job was reaped or claimed by someone else.
+pub async fn keep_alive(pool: &PgPool, job_id: &str, worker_id: &str, lease: Duration) {
+    let mut interval = tokio::time::interval(lease / 3);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match pg::heartbeat(pool, job_id, worker_id, lease).await {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                tracing::warn!(job_id = %job_id, worker_id = %worker_id, "Lost job lease");
+                return;
+            }
+            Err(e) => tracing::error!("Failed to extend job lease: {:?}", e),
+        }
+    }
+}
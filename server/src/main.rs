@@ -1,16 +1,36 @@
+use std::sync::Arc;
+
 use actix_cors::Cors;
 use actix_web::{http::header::{AUTHORIZATION, CONTENT_TYPE}, web::{self, PayloadConfig}, App, HttpServer};
 use posemesh_domain_http::{config::Config, DomainClient};
 
-use crate::{domain::upload_for_job, models::{JobStatus, QueryJob}, ollama_client::pull_ollama_model};
+use crate::models::JobError;
 
 mod pg;
+mod admin;
 mod http;
 mod models;
 mod domain;
+mod downloader;
+mod events;
+mod frame;
 mod stream;
+mod uploader;
 mod config;
+mod dedup;
+mod cancel;
+mod executor;
+mod handlers;
+mod lease;
+mod metrics;
+mod pipelines;
+mod preprocess;
+mod webhook;
+mod host_pool;
+mod inference;
 mod ollama_client;
+mod openai_client;
+mod recording;
 
 pub fn init_tracing() -> tracing::span::Span {
     let machine_id = match machine_uid::get() {
@@ -42,51 +62,86 @@ async fn main() {
     let span = init_tracing();
     let _guard = span.enter();
 
-    let pool = pg::init_pg(&pg::Config::from_env().expect("Failed to initialize pg config")).await.expect("Failed to initialize database");
+    let pg_config = pg::Config::from_env().expect("Failed to initialize pg config");
+    let pool = pg::init_pg(&pg_config).await.expect("Failed to initialize database");
+    let notifier = pg::JobNotifier::listen(&pool, std::time::Duration::from_secs(pg_config.notify_fallback_interval)).await.expect("Failed to listen for job notifications");
     let vlm_config = config::Config::from_env().expect("Failed to initialize vlm config");
 
-    pull_ollama_model(&vlm_config.model, &vlm_config.ollama_host).await.expect("Failed to pull ollama model");
+    let executor_config = executor::Config::from_env().expect("Failed to initialize executor config");
+    let lease_config = lease::Config::from_env().expect("Failed to initialize lease config");
+    let upload_config = uploader::Config::from_env().expect("Failed to initialize upload config");
+    let webhook_config = webhook::Config::from_env().expect("Failed to initialize webhook config");
+    let admin_config = web::Data::new(admin::Config::from_env().expect("Failed to initialize admin config"));
+    let recording_config = web::Data::new(recording::Config::from_env().expect("Failed to initialize recording config"));
+
+    let host_pool = inference::from_config(&vlm_config);
+    host_pool.spawn_health_checks(std::time::Duration::from_secs(vlm_config.inference_health_interval));
+    let backend: Arc<dyn inference::InferenceBackend> = host_pool.clone();
+    // Models are pulled in the background, `/ready` fails until they are available
+    inference::spawn_ensure_models(backend.clone(), vlm_config.allowed_models.clone());
 
     let domain_config = Config::from_env().expect("Failed to initialize domain config");
-    let domain_client = DomainClient::new_with_user_credential(&domain_config.api_url, &domain_config.dds_url, &domain_config.client_id, &domain_config.email.as_ref().unwrap(), &domain_config.password.as_ref().unwrap(), false).await.expect("Failed to initialize domain client");
+    let domain_client = DomainClient::new_with_user_credential(&domain_config.api_url, &domain_config.dds_url, &domain_config.client_id, domain_config.email.as_ref().unwrap(), domain_config.password.as_ref().unwrap(), false).await.expect("Failed to initialize domain client");
     let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "../data".to_string());
 
-    let domain_client_clone = domain_client.clone();
-    let data_dir_clone = data_dir.clone();
+    let registry = Arc::new(handlers::JobRegistry::with_default_handlers());
+
+    if executor_config.enabled {
+        executor::spawn(&executor_config, &lease_config, pool.clone(), notifier.clone(), registry.clone(), backend.clone(), vlm_config.clone(), data_dir.clone());
+    }
+
+    cancel::spawn_sweeper(pool.clone(), data_dir.clone(), std::time::Duration::from_secs(executor_config.cancel_grace_period));
+
     let pool_clone = pool.clone();
+    let reaper_data_dir = data_dir.clone();
+    let reaper_interval = std::time::Duration::from_secs(lease_config.reaper_interval);
+    let max_attempts = lease_config.max_attempts;
+    let lease = lease_config.duration();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+        let mut interval = tokio::time::interval(reaper_interval);
+        let err = JobError {
+            code: "lease_expired".to_string(),
+            message: format!("Job lease expired after {} attempts", max_attempts),
+        };
+        let download_err = JobError {
+            code: "download_interrupted".to_string(),
+            message: "The server downloading the domain data stopped, retry the job to download it again".to_string(),
+        };
         loop {
             interval.tick().await;
-            let jobs = pg::list_jobs(&pool_clone, 1, 0, Some(QueryJob {
-                status: Some(JobStatus::Uploading),
-                job_type: None,
-            })).await;
-            if let Ok(jobs) = jobs {
-                if jobs.is_empty() {
-                    continue;
-                }
-                let job = &jobs[0];
-                let job_id = &job.common.id;
-                let data_dir = format!("{}/output/{}", &data_dir_clone, job_id);
-                if !std::path::Path::new(&data_dir).exists() {
-                    continue;
-                }
-                let res = upload_for_job(&domain_client_clone, &job.common.domain_id, &data_dir).await;
-                if let Err(e) = res {
-                    if let Err(e) = pg::fail_job(&pool_clone, job_id, &e.to_string(), &job.common.updated_at).await {
-                        tracing::error!("Failed to fail job: {:?}", e);
+            match pg::reap_expired_jobs(&pool_clone, max_attempts, &err).await {
+                Ok(jobs) => {
+                    for job in jobs {
+                        tracing::warn!(job_id = %job.common.id, status = ?job.common.status, attempts = job.attempts, "Reaped job with expired lease");
                     }
                 }
-                if let Err(e) = pg::complete_job(&pool_clone, job_id).await {
-                    tracing::error!("Failed to complete job: {:?}", e);
+                Err(e) => tracing::error!("Failed to reap expired jobs: {:?}", e),
+            }
+            match pg::fail_expired_downloads(&pool_clone, lease, &download_err).await {
+                Ok(jobs) => {
+                    for job in jobs {
+                        tracing::warn!(job_id = %job.common.id, "Failed interrupted download");
+                        cancel::cleanup_job_data(&reaper_data_dir, &job.common.id).await;
+                    }
                 }
-            } else {
-                tracing::error!("Failed to list jobs: {:?}", jobs.err());
+                Err(e) => tracing::error!("Failed to fail interrupted downloads: {:?}", e),
             }
         }
     });
-    
+
+    uploader::spawn(upload_config, &lease_config, pool.clone(), notifier.clone(), domain_client.clone(), data_dir.clone());
+    webhook::spawn(webhook_config, pool.clone(), notifier.clone()).expect("Failed to initialize webhook client");
+    metrics::spawn_job_state_observer(notifier.clone());
+    if let Some(retention) = recording_config.retention {
+        recording::spawn_cleanup(pool.clone(), data_dir.clone(), retention);
+    }
+
+    let downloader = downloader::Downloader::new(&lease_config, pool.clone(), notifier.clone(), domain_client.clone(), data_dir.clone());
+
+    let admin_pool = pool.clone();
+    let admin_data_dir = data_dir.clone();
+    let admin_vlm_config = vlm_config.clone();
+    let admin_backend = backend.clone();
     let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
@@ -96,9 +151,13 @@ async fn main() {
             .max_age(3600);
         App::new()
             .app_data(web::Data::new(pool.clone()))
-            .app_data(web::Data::new(domain_client.clone()))
+            .app_data(web::Data::new(notifier.clone()))
+            .app_data(web::Data::new(downloader.clone()))
+            .app_data(recording_config.clone())
             .app_data(web::Data::new(data_dir.clone()))
             .app_data(web::Data::new(vlm_config.clone()))
+            .app_data(web::Data::from(registry.clone()))
+            .app_data(web::Data::from(backend.clone()))
             .app_data(PayloadConfig::new(2_usize.pow(20)))
             .wrap(cors)
             .configure(http::app_config)
@@ -112,7 +171,13 @@ async fn main() {
 
     let admin_server = HttpServer::new(move || {
         App::new()
-        .route("/health", web::get().to(|| async { "OK" }))
+            .app_data(web::Data::new(admin_pool.clone()))
+            .app_data(web::Data::new(admin_data_dir.clone()))
+            .app_data(web::Data::new(admin_vlm_config.clone()))
+            .app_data(web::Data::from(admin_backend.clone()))
+            .app_data(web::Data::from(host_pool.clone()))
+            .app_data(admin_config.clone())
+            .configure(admin::admin_config)
     })
         .bind(format!(
             "0.0.0.0:{}",
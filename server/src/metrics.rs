@@ -0,0 +1,138 @@
+use std::sync::{LazyLock, Mutex};
+
+use prometheus::{exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{models::JobStatus, pg::{self, JobEvent, JobNotifier}};
+
+/// Every metric the server exports on `/metrics`. They are process wide, so that the modules
+/// they measure can update them without having them passed around.
+pub struct Metrics {
+    registry: Registry,
+    /// Jobs in the database, refreshed on every scrape. The same on every server.
+    pub jobs: IntGaugeVec,
+    /// Only the transitions made by this server.
+    pub job_state_duration: HistogramVec,
+    pub domain_bytes: IntCounterVec,
+    pub domain_duration: HistogramVec,
+    pub ollama_request_duration: HistogramVec,
+    pub ollama_tokens_per_second: HistogramVec,
+    pub ollama_errors: IntCounterVec,
+    pub ws_sessions: IntGauge,
+    pub ws_frames_received: IntCounter,
+    pub ws_frames_batched: IntCounter,
+    pub ws_frames_dropped: IntCounterVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: T) -> T {
+    registry.register(Box::new(metric.clone())).expect("Failed to register metric");
+    metric
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        // From 100ms to about 1.7 hours, jobs and transfers can take anything in between
+        let long_buckets = exponential_buckets(0.1, 4.0, 9).unwrap();
+        Metrics {
+            jobs: register(&registry, IntGaugeVec::new(
+                Opts::new("vlm_jobs", "Jobs in the database by status and job type"),
+                &["status", "job_type"],
+            ).unwrap()),
+            job_state_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("vlm_job_state_duration_seconds", "Time jobs spent in a status before moving to the next one")
+                    .buckets(long_buckets.clone()),
+                &["status", "job_type"],
+            ).unwrap()),
+            domain_bytes: register(&registry, IntCounterVec::new(
+                Opts::new("vlm_domain_bytes_total", "Bytes downloaded from and uploaded to domain servers"),
+                &["direction"],
+            ).unwrap()),
+            domain_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("vlm_domain_transfer_duration_seconds", "Duration of domain data downloads and uploads")
+                    .buckets(long_buckets),
+                &["direction", "result"],
+            ).unwrap()),
+            ollama_request_duration: register(&registry, HistogramVec::new(
+                HistogramOpts::new("vlm_ollama_request_duration_seconds", "Time from sending a request to Ollama until its response is complete")
+                    .buckets(exponential_buckets(0.05, 2.0, 14).unwrap()),
+                &["endpoint", "model"],
+            ).unwrap()),
+            ollama_tokens_per_second: register(&registry, HistogramVec::new(
+                HistogramOpts::new("vlm_ollama_tokens_per_second", "Generation speed reported by Ollama")
+                    .buckets(exponential_buckets(1.0, 2.0, 10).unwrap()),
+                &["model"],
+            ).unwrap()),
+            ollama_errors: register(&registry, IntCounterVec::new(
+                Opts::new("vlm_ollama_errors_total", "Failed Ollama requests"),
+                &["endpoint"],
+            ).unwrap()),
+            ws_sessions: register(&registry, IntGauge::new("vlm_ws_active_sessions", "Open WebSocket sessions").unwrap()),
+            ws_frames_received: register(&registry, IntCounter::new("vlm_ws_frames_received_total", "Frames received over WebSocket").unwrap()),
+            ws_frames_batched: register(&registry, IntCounter::new("vlm_ws_frames_batched_total", "WebSocket frames sent to the model in a batch").unwrap()),
+            ws_frames_dropped: register(&registry, IntCounterVec::new(
+                Opts::new("vlm_ws_frames_dropped_total", "WebSocket frames that did not get a response"),
+                &["reason"],
+            ).unwrap()),
+            registry,
+        }
+    }
+}
+
+/// The status as it is stored in the database.
+fn status_label(status: &JobStatus) -> String {
+    serde_json::to_value(status)
+        .ok()
+        .and_then(|status| status.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Records how long jobs stay in each status from the `status_changed` events, which carry
+/// the time spent in the previous status. Every server receives every event, but only
+/// observes the transitions it made itself, so that the histograms of all servers add up.
+pub fn spawn_job_state_observer(notifier: JobNotifier) {
+    let mut subscription = notifier.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let notification = match subscription.recv().await {
+                Ok(notification) => notification,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Missed {} job events for metrics", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if notification.event != JobEvent::StatusChanged || notification.origin.as_deref() != Some(pg::application_name()) {
+                continue;
+            }
+            if let (Some(previous), Some(seconds)) = (&notification.previous_status, notification.previous_status_seconds) {
+                METRICS.job_state_duration
+                    .with_label_values(&[status_label(previous).as_str(), notification.job_type.as_str()])
+                    .observe(seconds);
+            }
+        }
+    });
+}
+
+/// Held while the job counts are replaced and gathered, so that concurrent scrapes don't
+/// see the counts of another scrape half written.
+static RENDER: Mutex<()> = Mutex::new(());
+
+/// Refreshes the job counts and encodes every metric in the Prometheus text format.
+pub async fn render(pool: &PgPool) -> Result<String, Box<dyn std::error::Error>> {
+    let counts = pg::count_jobs(pool).await?;
+    let families = {
+        let _guard = RENDER.lock().unwrap();
+        METRICS.jobs.reset();
+        for (status, job_type, count) in counts {
+            METRICS.jobs.with_label_values(&[status.as_str(), job_type.as_str()]).set(count);
+        }
+        METRICS.registry.gather()
+    };
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&families, &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -1,10 +1,11 @@
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono;
 
-#[derive(Serialize, Deserialize, Debug, sqlx::Type, Clone)]
+#[derive(Serialize, Deserialize, Debug, sqlx::Type, Clone, PartialEq, Eq)]
 #[serde(rename_all="snake_case")]
 #[sqlx(rename_all="lowercase", type_name="text")]
 pub enum JobStatus {
+    Downloading,
     Pending,
     Running,
     Completed,
@@ -15,7 +16,7 @@ pub enum JobStatus {
     Uploading,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JobError {
     pub code: String,
     pub message: String,
@@ -28,7 +29,8 @@ pub struct JobCommon {
     pub status: JobStatus,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
-    pub domain_id: String,
+    /// Unset for jobs that don't belong to a domain, e.g. recordings of sessions without one.
+    pub domain_id: Option<String>,
     pub query: serde_json::Value,
     #[sqlx(skip)]
     pub hash: String,
@@ -43,6 +45,58 @@ pub struct Job {
     pub output: Option<serde_json::Value>,
     pub error: Option<serde_json::Value>,
     pub job_type: String,
+    pub downloaded_files: i64,
+    pub downloaded_bytes: i64,
+    pub claimed_by: Option<String>,
+    pub lease_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub attempts: i32,
+}
+
+/// One run of a job, from the moment it was claimed until it completed, failed, was cancelled
+/// or lost its lease.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct JobAttempt {
+    pub id: i64,
+    pub job_id: String,
+    pub attempt: i32,
+    #[sqlx(rename = "job_status")]
+    pub status: JobStatus,
+    pub worker: Option<String>,
+    pub input: serde_json::Value,
+    pub output: Option<serde_json::Value>,
+    /// Kept as plain JSON like `Job::error`, attempts written by older workers have numeric codes.
+    pub error: Option<serde_json::Value>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub ended_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::Type, Clone, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+#[sqlx(rename_all="lowercase", type_name="text")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// A webhook call queued when a job completed or failed, retried until the receiver accepts it.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub job_id: String,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    pub payload: serde_json::Value,
+    #[sqlx(rename = "delivery_status")]
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub delivered_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Deserialize, Debug)]
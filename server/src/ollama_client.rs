@@ -1,14 +1,22 @@
-use actix_web::rt;
-use bytes::Bytes;
-use futures::{channel::mpsc, SinkExt};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use futures_util::StreamExt;
+use std::time::Instant;
+
+use async_trait::async_trait;
 use base64::Engine;
+use futures::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{config, inference::{collect, decode_lines, http_client, same_model, ChatMessage, ChunkStream, Error, GenerateChunk, GenerateRequest, InferenceBackend, ModelNotFound, PullProgress, PullStream, RetryPolicy}, metrics::METRICS};
 
 #[derive(Deserialize)]
 struct OllamaPullResponse {
-    status: String
+    #[serde(default)]
+    status: String,
+    digest: Option<String>,
+    completed: Option<u64>,
+    total: Option<u64>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -21,119 +29,260 @@ struct OllamaListModelsResponse {
     models: Vec<OllamaModel>
 }
 
-/// Pulls a model from Ollama by making a POST request to the Ollama server.
-/// Returns Ok(()) if the pull was successful, or an error otherwise.
-pub async fn pull_ollama_model(model_name: &str, ollama_host: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+#[derive(Deserialize)]
+struct OllamaResponse {
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    done: bool,
+    eval_count: Option<u64>,
+    eval_duration: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct OllamaChatMessage {
+    #[serde(default)]
+    content: String,
+}
 
-    // Check if the model exists before pulling
-    let check_url = format!("{}/api/tags", ollama_host);
-    let check_resp = client
-        .get(&check_url)
-        .send()
-        .await?;
+#[derive(Deserialize)]
+struct OllamaChatResponse {
+    #[serde(default)]
+    message: OllamaChatMessage,
+    #[serde(default)]
+    done: bool,
+    eval_count: Option<u64>,
+    eval_duration: Option<u64>,
+    error: Option<String>,
+}
 
-    if check_resp.status().is_success() {
-        let resp_json: OllamaListModelsResponse = check_resp.json().await?;
-        if resp_json.models.iter().any(|model| model.model == model_name) {
-            tracing::info!("Model '{}' already exists on Ollama, skipping pull.", model_name);
-            return Ok(());
-        }
-    }
+/// A chunk with the generation stats Ollama adds to the last one.
+struct OllamaChunk {
+    chunk: GenerateChunk,
+    /// Tokens generated.
+    eval_count: Option<u64>,
+    /// Nanoseconds spent generating them.
+    eval_duration: Option<u64>,
+}
+
+fn encode_images(images: &[Vec<u8>]) -> Vec<String> {
+    images
+        .iter()
+        .map(|img| base64::engine::general_purpose::STANDARD.encode(img))
+        .collect()
+}
 
-    let url = format!("{}/api/pull", ollama_host);
-    let body = json!({ "model": model_name, "stream": true });
-    tracing::info!("Pulling model {} from Ollama: {:?}", model_name, url);
+fn parse_response_line(line: &[u8]) -> Result<Option<OllamaChunk>, Error> {
+    let response: OllamaResponse = serde_json::from_slice(line)?;
+    if let Some(error) = response.error {
+        return Err(error.into());
+    }
+    Ok(Some(OllamaChunk {
+        chunk: GenerateChunk {
+            response: response.response,
+            done: response.done,
+        },
+        eval_count: response.eval_count,
+        eval_duration: response.eval_duration,
+    }))
+}
 
-    let resp = client
-        .post(url)
-        .json(&body)
-        .send()
-        .await?;
+fn parse_chat_line(line: &[u8]) -> Result<Option<OllamaChunk>, Error> {
+    let response: OllamaChatResponse = serde_json::from_slice(line)?;
+    if let Some(error) = response.error {
+        return Err(error.into());
+    }
+    Ok(Some(OllamaChunk {
+        chunk: GenerateChunk {
+            response: response.message.content,
+            done: response.done,
+        },
+        eval_count: response.eval_count,
+        eval_duration: response.eval_duration,
+    }))
+}
 
-    let mut stream = resp.bytes_stream();
+fn record_error(endpoint: &str) {
+    METRICS.ollama_errors.with_label_values(&[endpoint]).inc();
+}
 
-    tracing::info!("Received response from Ollama pull");
-    while let Some(chunk) = stream.next().await {
-        if let Ok(chunk) = chunk {
-            let response: OllamaPullResponse = serde_json::from_slice(&chunk).unwrap();
-            if response.status == "success" {
-                break;
+/// Records the latency, generation speed and errors of a request as its response streams by.
+fn instrument(endpoint: &'static str, model: String, started: Instant, chunks: BoxStream<'static, Result<OllamaChunk, Error>>) -> ChunkStream {
+    chunks.map(move |chunk| {
+        match &chunk {
+            Ok(chunk) if chunk.chunk.done => {
+                METRICS.ollama_request_duration
+                    .with_label_values(&[endpoint, model.as_str()])
+                    .observe(started.elapsed().as_secs_f64());
+                if let (Some(count), Some(duration)) = (chunk.eval_count, chunk.eval_duration) && duration > 0 {
+                    METRICS.ollama_tokens_per_second
+                        .with_label_values(&[model.as_str()])
+                        .observe(count as f64 * 1e9 / duration as f64);
+                }
             }
-            tracing::info!("Received response from Ollama pull: {:?}", response.status);
-        } else {
-            let err = chunk.err().unwrap();
-            tracing::error!("Error sending chunk to channel: {:?}", err);
-            return Err(err.into());
+            Ok(_) => (),
+            Err(_) => record_error(endpoint),
         }
-    }
-    tracing::info!("Model '{}' pulled successfully", model_name);
+        chunk.map(|chunk| chunk.chunk)
+    }).boxed()
+}
 
-    Ok(())
+fn parse_pull_line(line: &[u8]) -> Result<Option<PullProgress>, Error> {
+    let response: OllamaPullResponse = serde_json::from_slice(line)?;
+    if let Some(error) = response.error {
+        return Err(error.into());
+    }
+    Ok(Some(PullProgress {
+        host: None,
+        status: response.status,
+        digest: response.digest,
+        completed: response.completed,
+        total: response.total,
+    }))
 }
 
-#[derive(Deserialize, Serialize)]
-pub struct OllamaResponse {
-    model: String,
-    created_at: String,
-    pub response: String,
-    pub done: bool,
+/// Talks to Ollama's native `/api` endpoints, reusing one pooled HTTP client for every request.
+/// Responses are always streamed, so that the read timeout only fires when Ollama stalls and
+/// not while it is still generating.
+pub struct OllamaClient {
+    host: String,
+    client: reqwest::Client,
+    retry: RetryPolicy,
 }
 
-pub async fn send_to_ollama(
-    images_batch: Vec<Vec<u8>>,
-    prompt: String,
-    model: String,
-    ollama_host: String,
-    num_predict: Option<i32>,
-) -> Result<mpsc::Receiver<Result<Bytes, reqwest::Error>>, reqwest::Error> {
-    tracing::info!("Sending images to Ollama: {:?}", images_batch.len());
-    let images_b64: Vec<String> = images_batch
-        .iter()
-        .map(|img| base64::engine::general_purpose::STANDARD.encode(img))
-        .collect();
-
-    let mut body = json!({
-        "prompt": prompt,
-        "images": images_b64,
-        "model": model.clone(),
-        "stream": true,
-    });
-
-    if let Some(num_predict_val) = num_predict {
-        body["options"] = json!({
-            "num_predict": num_predict_val
+impl OllamaClient {
+    pub fn new(host: &str, config: &config::Config) -> Self {
+        OllamaClient {
+            host: host.trim_end_matches('/').to_string(),
+            client: http_client(config),
+            retry: RetryPolicy::from_config(config),
+        }
+    }
+
+    fn generate_body(request: &GenerateRequest) -> serde_json::Value {
+        let mut body = json!({
+            "prompt": request.prompt,
+            "images": encode_images(&request.images),
+            "model": request.model,
+            "stream": true,
+            "options": request.options,
         });
+        if let Some(format) = &request.format {
+            body["format"] = format.clone();
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl InferenceBackend for OllamaClient {
+    async fn generate(&self, request: GenerateRequest) -> Result<String, Error> {
+        collect(self.generate_stream(request).await?).await
     }
 
-    let url = format!("{}/api/generate", ollama_host);
-
-    let resp = reqwest::Client::new()
-        .post(&url)
-        .json(&body)
-        .send()
-        .await?;
-  
-    let mut stream = resp.bytes_stream();
-    let (mut tx, rx) = mpsc::channel(100);
-
-    rt::spawn(async move {
-        tracing::info!("Received response from Ollama");
-        while let Some(chunk) = stream.next().await {
-            if let Ok(chunk) = chunk {
-                if let Err(e) = tx.send(Ok(chunk)).await {
-                    tracing::error!("Error sending chunk to channel: {:?}", e);
-                    break;
-                }
-            } else {
-                if let Err(e) = tx.send(Err(chunk.err().unwrap())).await {
-                    tracing::error!("Error sending chunk to channel: {:?}", e);
-                    break;
-                }
-                break;
+    async fn generate_stream(&self, request: GenerateRequest) -> Result<ChunkStream, Error> {
+        tracing::info!("Sending images to Ollama: {:?}", request.images.len());
+        let url = format!("{}/api/generate", self.host);
+        let started = Instant::now();
+        let resp = self.client
+            .post(&url)
+            .json(&Self::generate_body(&request))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .inspect_err(|_| record_error("generate"))?;
+        Ok(instrument("generate", request.model, started, decode_lines(resp, parse_response_line)))
+    }
+
+    async fn chat(&self, model: &str, messages: Vec<ChatMessage>, format: Option<&serde_json::Value>) -> Result<String, Error> {
+        let messages: Vec<serde_json::Value> = messages
+            .iter()
+            .map(|message| json!({
+                "role": message.role,
+                "content": message.content,
+                "images": encode_images(&message.images),
+            }))
+            .collect();
+        let mut body = json!({ "model": model, "messages": messages, "stream": true });
+        if let Some(format) = format {
+            body["format"] = format.clone();
+        }
+        let url = format!("{}/api/chat", self.host);
+        let started = Instant::now();
+        let resp = self.client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .inspect_err(|_| record_error("chat"))?;
+        collect(instrument("chat", model.to_string(), started, decode_lines(resp, parse_chat_line))).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Error> {
+        let url = format!("{}/api/tags", self.host);
+        let resp_json: OllamaListModelsResponse = self.retry.run("list models", || async {
+            self.client.get(&url).send().await?.error_for_status()?.json().await
+        }).await.inspect_err(|_| record_error("tags"))?;
+        Ok(resp_json.models.into_iter().map(|model| model.model).collect())
+    }
+
+    async fn ensure_model(&self, model: &str) -> Result<(), Error> {
+        // Check if the model exists before pulling
+        match self.list_models().await {
+            Ok(models) if models.iter().any(|m| same_model(m, model)) => {
+                tracing::info!("Model '{}' already exists on Ollama, skipping pull.", model);
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(e) => tracing::warn!("Failed to list Ollama models: {:?}", e),
+        }
+        let mut progress = self.pull_model(model).await?;
+        let mut status = String::new();
+        while let Some(line) = progress.next().await {
+            let line = line.map_err(|e| format!("Failed to pull model {}: {}", model, e))?;
+            if line.status == "success" {
+                tracing::info!("Model '{}' pulled successfully", model);
+                return Ok(());
+            }
+            // Downloads report progress many times a second, only log when the step changes
+            if line.status != status {
+                tracing::info!("Received response from Ollama pull: {:?}", line.status);
+                status = line.status;
             }
         }
-        tx.close().await.unwrap();
-    });
-    Ok(rx)
+        Err(format!("Pull of model {} ended before it succeeded", model).into())
+    }
+
+    /// Pulling again resumes a pull that was interrupted, so starting it is retried.
+    async fn pull_model(&self, model: &str) -> Result<PullStream, Error> {
+        let url = format!("{}/api/pull", self.host);
+        tracing::info!("Pulling model {} from Ollama: {:?}", model, url);
+        let resp = self.retry.run("start model pull", || async {
+            self.client
+                .post(&url)
+                .json(&json!({ "model": model, "stream": true }))
+                .send()
+                .await?
+                .error_for_status()
+        }).await.inspect_err(|_| record_error("pull"))?;
+        Ok(decode_lines(resp, parse_pull_line).inspect_err(|_| record_error("pull")).boxed())
+    }
+
+    async fn delete_model(&self, model: &str) -> Result<(), Error> {
+        let url = format!("{}/api/delete", self.host);
+        let resp = self.client
+            .delete(&url)
+            .json(&json!({ "model": model }))
+            .send()
+            .await
+            .inspect_err(|_| record_error("delete"))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(ModelNotFound(model.to_string()).into());
+        }
+        resp.error_for_status().inspect_err(|_| record_error("delete"))?;
+        tracing::info!("Model '{}' deleted from Ollama", model);
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{config, inference::{collect, decode_lines, http_client, image_mime_type, same_model, ChatMessage, ChunkStream, Error, GenerateChunk, GenerateOptions, GenerateRequest, InferenceBackend, PullStream, RetryPolicy}};
+
+#[derive(Deserialize)]
+struct ChatCompletionDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChunkChoice {
+    delta: ChatCompletionDelta,
+}
+
+#[derive(Deserialize)]
+struct ChatCompletionChunk {
+    choices: Vec<ChatCompletionChunkChoice>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiModel {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct OpenAiListModelsResponse {
+    data: Vec<OpenAiModel>,
+}
+
+/// Builds an OpenAI message, sending images as `image_url` parts with data URLs.
+fn to_openai_message(message: &ChatMessage) -> serde_json::Value {
+    if message.images.is_empty() {
+        return json!({ "role": message.role, "content": message.content });
+    }
+    let mut content = vec![json!({ "type": "text", "text": message.content })];
+    content.extend(message.images.iter().map(|image| json!({
+        "type": "image_url",
+        "image_url": {
+            "url": format!(
+                "data:{};base64,{}",
+                image_mime_type(image),
+                base64::engine::general_purpose::STANDARD.encode(image)
+            ),
+        },
+    })));
+    json!({ "role": message.role, "content": content })
+}
+
+/// Maps an Ollama style `format` to `response_format`.
+fn response_format(format: &serde_json::Value) -> serde_json::Value {
+    match format {
+        serde_json::Value::Object(_) => json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": format },
+        }),
+        _ => json!({ "type": "json_object" }),
+    }
+}
+
+/// Parses one line of the Server-Sent Events stream of a chat completion.
+fn parse_event_line(line: &[u8]) -> Result<Option<GenerateChunk>, Error> {
+    let Some(data) = line.strip_prefix(b"data:") else {
+        return Ok(None);
+    };
+    let data = data.trim_ascii();
+    if data == b"[DONE]" {
+        return Ok(Some(GenerateChunk {
+            response: String::new(),
+            done: true,
+        }));
+    }
+    let chunk: ChatCompletionChunk = serde_json::from_slice(data)?;
+    let content = chunk.choices.into_iter().next().and_then(|choice| choice.delta.content);
+    Ok(content.map(|response| GenerateChunk { response, done: false }))
+}
+
+/// Talks to servers implementing the OpenAI `/v1/chat/completions` API, such as vLLM,
+/// llama.cpp server or LM Studio. These servers can't pull models, they serve what they
+/// were started with.
+pub struct OpenAiClient {
+    host: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl OpenAiClient {
+    pub fn new(host: &str, config: &config::Config) -> Self {
+        OpenAiClient {
+            host: host.trim_end_matches('/').to_string(),
+            api_key: config.inference_api_key.clone(),
+            client: http_client(config),
+            retry: RetryPolicy::from_config(config),
+        }
+    }
+
+    async fn chat_completion(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        options: &GenerateOptions,
+        format: Option<&serde_json::Value>,
+    ) -> Result<ChunkStream, Error> {
+        // Always streamed, so that the read timeout only fires when the server stalls
+        let mut body = json!({
+            "model": model,
+            "messages": messages.iter().map(to_openai_message).collect::<Vec<_>>(),
+            "stream": true,
+        });
+        // num_ctx is fixed when an OpenAI compatible server loads the model
+        // A negative num_predict means no limit, which is the default of max_tokens
+        if let Some(max_tokens) = options.num_predict.filter(|n| *n > 0) {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(temperature) = options.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = options.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(seed) = options.seed {
+            body["seed"] = json!(seed);
+        }
+        if !options.stop.is_empty() {
+            body["stop"] = json!(options.stop);
+        }
+        if let Some(format) = format {
+            body["response_format"] = response_format(format);
+        }
+        let mut request = self.client
+            .post(format!("{}/v1/chat/completions", self.host))
+            .json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let resp = request.send().await?.error_for_status()?;
+        Ok(decode_lines(resp, parse_event_line))
+    }
+
+    fn prompt_message(request: &GenerateRequest) -> ChatMessage {
+        ChatMessage {
+            role: "user".to_string(),
+            content: request.prompt.clone(),
+            images: request.images.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl InferenceBackend for OpenAiClient {
+    async fn generate(&self, request: GenerateRequest) -> Result<String, Error> {
+        collect(self.generate_stream(request).await?).await
+    }
+
+    async fn generate_stream(&self, request: GenerateRequest) -> Result<ChunkStream, Error> {
+        tracing::info!("Sending images to inference server: {:?}", request.images.len());
+        let messages = [Self::prompt_message(&request)];
+        self.chat_completion(&request.model, &messages, &request.options, request.format.as_ref()).await
+    }
+
+    async fn chat(&self, model: &str, messages: Vec<ChatMessage>, format: Option<&serde_json::Value>) -> Result<String, Error> {
+        collect(self.chat_completion(model, &messages, &GenerateOptions::default(), format).await?).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, Error> {
+        let url = format!("{}/v1/models", self.host);
+        let resp: OpenAiListModelsResponse = self.retry.run("list models", || async {
+            let mut request = self.client.get(&url);
+            if let Some(api_key) = &self.api_key {
+                request = request.bearer_auth(api_key);
+            }
+            request.send().await?.error_for_status()?.json().await
+        }).await?;
+        Ok(resp.data.into_iter().map(|model| model.id).collect())
+    }
+
+    async fn ensure_model(&self, model: &str) -> Result<(), Error> {
+        let models = self.list_models().await?;
+        if !models.iter().any(|m| same_model(m, model)) {
+            return Err(format!("Model {} is not served by {}, available models: {}", model, self.host, models.join(", ")).into());
+        }
+        tracing::info!("Model '{}' is available", model);
+        Ok(())
+    }
+
+    async fn pull_model(&self, _model: &str) -> Result<PullStream, Error> {
+        Err(format!("Models can't be pulled through the OpenAI API of {}", self.host).into())
+    }
+
+    async fn delete_model(&self, _model: &str) -> Result<(), Error> {
+        Err(format!("Models can't be deleted through the OpenAI API of {}", self.host).into())
+    }
+}
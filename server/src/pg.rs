@@ -1,10 +1,14 @@
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::LazyLock;
 use std::time::Duration;
-use sqlx::postgres::PgPoolOptions;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgConnectOptions, PgListener, PgPoolOptions};
 use sqlx::migrate::Migrator;
 use sqlx::PgPool;
+use tokio::sync::broadcast;
 
-use crate::models::{Job, JobStatus, QueryJob};
+use crate::models::{DeliveryStatus, Job, JobAttempt, JobError, JobStatus, QueryJob, WebhookDelivery};
 
 pub struct Config {
     pub postgres_url: String,
@@ -12,6 +16,7 @@ pub struct Config {
     pub postgres_pool_idle_timeout: u64,
     pub postgres_pool_connection_timeout: u64,
     pub migrations_path: String,
+    pub notify_fallback_interval: u64,
 }
 
 impl Config {
@@ -22,16 +27,26 @@ impl Config {
             postgres_pool_idle_timeout: std::env::var("POSTGRES_POOL_IDLE_TIMEOUT").unwrap_or("300".to_string()).parse::<u64>()?,
             postgres_pool_connection_timeout: std::env::var("POSTGRES_POOL_CONNECTION_TIMEOUT").unwrap_or("10".to_string()).parse::<u64>()?,
             migrations_path: std::env::var("MIGRATIONS_PATH").unwrap_or("migrations".to_string()),
+            notify_fallback_interval: std::env::var("POSTGRES_NOTIFY_FALLBACK_INTERVAL").unwrap_or("30".to_string()).parse::<u64>()?,
         })
     }
 }
 
+/// Identifies the connections of this process, job events carry it as their `origin`.
+/// Random so that it is unique and fits in the 63 bytes Postgres keeps.
+static APPLICATION_NAME: LazyLock<String> = LazyLock::new(|| format!("vlm-node-{}", uuid::Uuid::new_v4().simple()));
+
+pub fn application_name() -> &'static str {
+    &APPLICATION_NAME
+}
+
 pub async fn init_pg(config: &Config) -> Result<PgPool, Box<dyn std::error::Error>> {
+    let options = PgConnectOptions::from_str(&config.postgres_url)?.application_name(application_name());
     let pool = PgPoolOptions::new()
         .max_connections(config.postgres_pool_size)
         .idle_timeout(Duration::from_secs(config.postgres_pool_idle_timeout))
         .acquire_timeout(Duration::from_secs(config.postgres_pool_connection_timeout))
-        .connect(&config.postgres_url)
+        .connect_with(options)
         .await?;
 
     let migrator = Migrator::new(Path::new(&config.migrations_path))
@@ -42,18 +57,134 @@ pub async fn init_pg(config: &Config) -> Result<PgPool, Box<dyn std::error::Erro
     Ok(pool)
 }
 
+/// Checks that a connection can be taken from the pool and used.
+pub async fn ping(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+const JOB_STATUS_CHANNEL: &str = "job_status";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobEvent {
+    Created,
+    StatusChanged,
+    OutputReady,
+}
+
+impl JobEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobEvent::Created => "created",
+            JobEvent::StatusChanged => "status_changed",
+            JobEvent::OutputReady => "output_ready",
+        }
+    }
+}
+
+/// Payload sent by the `jobs_notify_status` trigger whenever a job is created, changes status
+/// or gets its output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobNotification {
+    pub event: JobEvent,
+    pub id: String,
+    pub status: JobStatus,
+    pub previous_status: Option<JobStatus>,
+    /// Seconds the job spent in `previous_status`, only used for metrics.
+    #[serde(default, skip_serializing)]
+    pub previous_status_seconds: Option<f64>,
+    /// Application name of the connection that changed the status, only used for metrics.
+    #[serde(default, skip_serializing)]
+    pub origin: Option<String>,
+    pub job_type: String,
+    pub domain_id: Option<String>,
+}
+
+/// Fans out job status notifications from a single `LISTEN` connection to in-process consumers.
+#[derive(Clone)]
+pub struct JobNotifier {
+    tx: broadcast::Sender<JobNotification>,
+    fallback_interval: Duration,
+}
+
+impl JobNotifier {
+    pub async fn listen(pool: &PgPool, fallback_interval: Duration) -> Result<Self, sqlx::Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(JOB_STATUS_CHANNEL).await?;
+        let (tx, _) = broadcast::channel(1024);
+        let sender = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                // The listener reconnects by itself, notifications sent while it is down are
+                // lost and picked up by the consumers' fallback poll.
+                match listener.recv().await {
+                    Ok(notification) => match serde_json::from_str::<JobNotification>(notification.payload()) {
+                        Ok(notification) => {
+                            let _ = sender.send(notification);
+                        }
+                        Err(e) => tracing::warn!("Failed to parse job notification: {:?}", e),
+                    },
+                    Err(e) => {
+                        tracing::error!("Failed to receive job notification: {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+        Ok(JobNotifier { tx, fallback_interval })
+    }
+
+    pub fn subscribe(&self) -> JobSubscription {
+        JobSubscription {
+            rx: self.tx.subscribe(),
+            fallback_interval: self.fallback_interval,
+        }
+    }
+}
+
+pub struct JobSubscription {
+    rx: broadcast::Receiver<JobNotification>,
+    fallback_interval: Duration,
+}
+
+impl JobSubscription {
+    pub async fn recv(&mut self) -> Result<JobNotification, broadcast::error::RecvError> {
+        self.rx.recv().await
+    }
+
+    /// Waits for a notification matching `predicate`. Returns `false` when the fallback
+    /// interval elapsed or notifications were dropped, in which case the caller should poll.
+    pub async fn wait_for(&mut self, predicate: impl Fn(&JobNotification) -> bool) -> bool {
+        let wait = async {
+            loop {
+                match self.rx.recv().await {
+                    Ok(notification) if predicate(&notification) => return true,
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => return false,
+                    Err(broadcast::error::RecvError::Closed) => std::future::pending::<()>().await,
+                }
+            }
+        };
+        tokio::time::timeout(self.fallback_interval, wait).await.unwrap_or(false)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn create_job(
     pool: &PgPool,
     id: &str,
     domain_id: &str,
     query: &serde_json::Value,
     input: &serde_json::Value,
+    webhook_secret: Option<&str>,
     job_type: &str,
+    status: &JobStatus,
 ) -> Result<Job, sqlx::Error> {
     let rec = sqlx::query_as::<_, Job>(
         "
-        INSERT INTO jobs (id, domain_id, query, input, job_type, job_status)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        INSERT INTO jobs (id, domain_id, query, input, webhook_secret, job_type, job_status)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
         RETURNING *
         "
     )
@@ -61,8 +192,39 @@ pub async fn create_job(
     .bind(domain_id)
     .bind(query)
     .bind(input)
+    .bind(webhook_secret)
     .bind(job_type)
-    .bind(JobStatus::Pending)
+    .bind(status)
+    .fetch_one(pool)
+    .await?;
+    Ok(rec)
+}
+
+/// Inserts a job that is already `completed`, for records such as session recordings that
+/// have nothing left to run.
+pub async fn create_completed_job(
+    pool: &PgPool,
+    id: &str,
+    domain_id: Option<&str>,
+    query: &serde_json::Value,
+    input: &serde_json::Value,
+    output: &serde_json::Value,
+    job_type: &str,
+) -> Result<Job, sqlx::Error> {
+    let rec = sqlx::query_as::<_, Job>(
+        "
+        INSERT INTO jobs (id, domain_id, query, input, output, job_type, job_status)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
+        "
+    )
+    .bind(id)
+    .bind(domain_id)
+    .bind(query)
+    .bind(input)
+    .bind(output)
+    .bind(job_type)
+    .bind(JobStatus::Completed)
     .fetch_one(pool)
     .await?;
     Ok(rec)
@@ -96,6 +258,15 @@ pub async fn list_jobs(
     Ok(jobs)
 }
 
+/// Number of jobs per status and job type.
+pub async fn count_jobs(pool: &PgPool) -> Result<Vec<(String, String, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, String, i64)>(
+        "SELECT job_status, job_type, count(*) FROM jobs GROUP BY job_status, job_type"
+    )
+    .fetch_all(pool)
+    .await
+}
+
 pub async fn get_job_by_id(
     pool: &PgPool,
     id: &str,
@@ -113,23 +284,313 @@ pub async fn get_job_by_id(
     Ok(job)
 }
 
+pub async fn list_job_attempts(
+    pool: &PgPool,
+    job_id: &str,
+) -> Result<Vec<JobAttempt>, sqlx::Error> {
+    let attempts = sqlx::query_as::<_, JobAttempt>(
+        r#"
+        SELECT *
+        FROM job_attempts
+        WHERE job_id = $1
+        ORDER BY attempt ASC
+        "#
+    )
+    .bind(job_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(attempts)
+}
+
+/// Resets a job to run again with a new `input`. The webhook secret is only replaced if a
+/// new one is given, clients can't send back a secret they never see.
 pub async fn retry_job(
     pool: &PgPool,
     id: &str,
     status: &JobStatus,
     input: &serde_json::Value,
+    webhook_secret: Option<&str>,
     updated_at: &chrono::DateTime<chrono::Utc>,
 ) -> Result<Option<Job>, sqlx::Error> {
     let job = sqlx::query_as::<_, Job>(
         r#"
         UPDATE jobs
-        SET job_status = $1, updated_at = now(), error = null, output = null, input = $2
-        WHERE id = $3 AND updated_at = $4
+        SET job_status = $1, updated_at = now(), error = null, output = null, input = $2,
+            webhook_secret = COALESCE($3, webhook_secret),
+            attempts = 0, claimed_by = null, lease_expires_at = null
+        WHERE id = $4 AND updated_at = $5
         RETURNING *
         "#
     )
     .bind(status)
     .bind(input)
+    .bind(webhook_secret)
+    .bind(id)
+    .bind(updated_at)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Claims a `downloading` job for the download running in `worker_id`.
+pub async fn claim_download(
+    pool: &PgPool,
+    id: &str,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET claimed_by = $1, lease_expires_at = now() + make_interval(secs => $2),
+            downloaded_files = 0, downloaded_bytes = 0
+        WHERE id = $3 AND job_status = $4
+        RETURNING *
+        "#
+    )
+    .bind(worker_id)
+    .bind(lease.as_secs_f64())
+    .bind(id)
+    .bind(JobStatus::Downloading)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Records download progress. Returns `None` once the job is no longer downloading.
+/// Like heartbeats, progress leaves `updated_at` untouched so that the job can still be
+/// cancelled with the `updated_at` a client read a moment ago.
+pub async fn update_download_progress(
+    pool: &PgPool,
+    id: &str,
+    files: i64,
+    bytes: i64,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET downloaded_files = $1, downloaded_bytes = $2
+        WHERE id = $3 AND job_status = $4
+        RETURNING *
+        "#
+    )
+    .bind(files)
+    .bind(bytes)
+    .bind(id)
+    .bind(JobStatus::Downloading)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Moves a downloading job to pending with its final download counts.
+pub async fn finish_download(
+    pool: &PgPool,
+    id: &str,
+    files: i64,
+    bytes: i64,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, downloaded_files = $2, downloaded_bytes = $3, updated_at = now(),
+            claimed_by = NULL, lease_expires_at = NULL
+        WHERE id = $4 AND job_status = $5
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Pending)
+    .bind(files)
+    .bind(bytes)
+    .bind(id)
+    .bind(JobStatus::Downloading)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+pub async fn fail_download(
+    pool: &PgPool,
+    id: &str,
+    error: &JobError,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, error = $2, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
+        WHERE id = $3 AND job_status = $4
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Failed)
+    .bind(sqlx::types::Json(error))
+    .bind(id)
+    .bind(JobStatus::Downloading)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Fails downloads whose lease expired, i.e. whose server stopped before they finished.
+/// Jobs that never got a lease are failed once they have been downloading for `lease`.
+pub async fn fail_expired_downloads(
+    pool: &PgPool,
+    lease: Duration,
+    error: &JobError,
+) -> Result<Vec<Job>, sqlx::Error> {
+    let jobs = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, error = $2, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
+        WHERE job_status = $3 AND (
+            lease_expires_at < now()
+            OR (lease_expires_at IS NULL AND updated_at < now() - make_interval(secs => $4))
+        )
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Failed)
+    .bind(sqlx::types::Json(error))
+    .bind(JobStatus::Downloading)
+    .bind(lease.as_secs_f64())
+    .fetch_all(pool)
+    .await?;
+    Ok(jobs)
+}
+
+/// Atomically moves the oldest pending job to running, claims it for `worker_id` and returns it.
+/// Concurrent executors skip rows that are already locked by another claim.
+pub async fn claim_next_job(
+    pool: &PgPool,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, updated_at = now(), claimed_by = $2,
+            lease_expires_at = now() + make_interval(secs => $3), attempts = attempts + 1
+        WHERE id = (
+            SELECT id
+            FROM jobs
+            WHERE job_status = $4
+            ORDER BY created_at ASC
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Running)
+    .bind(worker_id)
+    .bind(lease.as_secs_f64())
+    .bind(JobStatus::Pending)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Claims the oldest unclaimed uploading job for `worker_id`. Uploads count against the
+/// attempts of the job, so that an upload that keeps losing its lease eventually fails.
+pub async fn claim_upload(
+    pool: &PgPool,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET claimed_by = $1, lease_expires_at = now() + make_interval(secs => $2), attempts = attempts + 1
+        WHERE id = (
+            SELECT id
+            FROM jobs
+            WHERE job_status = $3 AND lease_expires_at IS NULL
+            ORDER BY updated_at ASC
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *
+        "#
+    )
+    .bind(worker_id)
+    .bind(lease.as_secs_f64())
+    .bind(JobStatus::Uploading)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Extends the lease held by `worker_id`. Returns `None` if the worker no longer holds it.
+/// `updated_at` is left untouched so that heartbeats don't break optimistic locking.
+pub async fn heartbeat(
+    pool: &PgPool,
+    id: &str,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET lease_expires_at = now() + make_interval(secs => $1)
+        WHERE id = $2 AND claimed_by = $3 AND lease_expires_at IS NOT NULL
+        RETURNING *
+        "#
+    )
+    .bind(lease.as_secs_f64())
+    .bind(id)
+    .bind(worker_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Releases jobs whose lease has expired. Running jobs go back to pending to run again,
+/// completing and uploading jobs go back to the upload queue since their output is already
+/// stored. Jobs that used up their attempts fail with `error` instead.
+/// Downloads are failed by `fail_expired_downloads` and `cancelling` jobs by `cancel_stale_jobs`.
+pub async fn reap_expired_jobs(
+    pool: &PgPool,
+    max_attempts: i32,
+    error: &JobError,
+) -> Result<Vec<Job>, sqlx::Error> {
+    let jobs = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = CASE WHEN attempts >= $1 THEN $2 WHEN job_status = $3 THEN $4 ELSE $5 END,
+            error = CASE WHEN attempts >= $1 THEN $6 ELSE error END,
+            claimed_by = NULL, lease_expires_at = NULL, updated_at = now()
+        WHERE job_status IN ($3, $7, $5) AND lease_expires_at < now()
+        RETURNING *
+        "#
+    )
+    .bind(max_attempts)
+    .bind(JobStatus::Failed)
+    .bind(JobStatus::Running)
+    .bind(JobStatus::Pending)
+    .bind(JobStatus::Uploading)
+    .bind(sqlx::types::Json(error))
+    .bind(JobStatus::Completing)
+    .fetch_all(pool)
+    .await?;
+    Ok(jobs)
+}
+
+pub async fn finish_processing(
+    pool: &PgPool,
+    id: &str,
+    output: &serde_json::Value,
+    updated_at: &chrono::DateTime<chrono::Utc>,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, output = $2, updated_at = now()
+        WHERE id = $3 AND updated_at = $4
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Completing)
+    .bind(output)
     .bind(id)
     .bind(updated_at)
     .fetch_optional(pool)
@@ -140,19 +601,19 @@ pub async fn retry_job(
 pub async fn fail_job(
     pool: &PgPool,
     id: &str,
-    error: &str,
+    error: &JobError,
     updated_at: &chrono::DateTime<chrono::Utc>,
 ) -> Result<Option<Job>, sqlx::Error> {
     let job = sqlx::query_as::<_, Job>(
         r#"
         UPDATE jobs
-        SET job_status = $1, error = $2, updated_at = now()
+        SET job_status = $1, error = $2, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
         WHERE id = $3 AND updated_at = $4
         RETURNING *
         "#
     )
     .bind(JobStatus::Failed)
-    .bind(error)
+    .bind(sqlx::types::Json(error))
     .bind(id)
     .bind(updated_at)
     .fetch_optional(pool)
@@ -160,21 +621,209 @@ pub async fn fail_job(
     Ok(job)
 }
 
-pub async fn complete_job(
+pub async fn set_job_status(
     pool: &PgPool,
     id: &str,
+    status: &JobStatus,
+    updated_at: &chrono::DateTime<chrono::Utc>,
 ) -> Result<Option<Job>, sqlx::Error> {
     let job = sqlx::query_as::<_, Job>(
         r#"
         UPDATE jobs
         SET job_status = $1, updated_at = now()
-        WHERE id = $2
+        WHERE id = $2 AND updated_at = $3
+        RETURNING *
+        "#
+    )
+    .bind(status)
+    .bind(id)
+    .bind(updated_at)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Moves a `cancelling` job to `cancelled`. Returns `None` if the job was not cancelling.
+pub async fn mark_cancelled(
+    pool: &PgPool,
+    id: &str,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
+        WHERE id = $2 AND job_status = $3
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Cancelled)
+    .bind(id)
+    .bind(JobStatus::Cancelling)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// Moves every job that has been `cancelling` for longer than `grace_period` to `cancelled`.
+pub async fn cancel_stale_jobs(
+    pool: &PgPool,
+    grace_period: Duration,
+) -> Result<Vec<Job>, sqlx::Error> {
+    let jobs = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
+        WHERE job_status = $2 AND updated_at < now() - make_interval(secs => $3)
+        RETURNING *
+        "#
+    )
+    .bind(JobStatus::Cancelled)
+    .bind(JobStatus::Cancelling)
+    .bind(grace_period.as_secs_f64())
+    .fetch_all(pool)
+    .await?;
+    Ok(jobs)
+}
+
+pub async fn complete_job(
+    pool: &PgPool,
+    id: &str,
+    updated_at: &chrono::DateTime<chrono::Utc>,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(
+        r#"
+        UPDATE jobs
+        SET job_status = $1, updated_at = now(), claimed_by = NULL, lease_expires_at = NULL
+        WHERE id = $2 AND updated_at = $3
         RETURNING *
         "#
     )
     .bind(JobStatus::Completed)
     .bind(id)
+    .bind(updated_at)
     .fetch_optional(pool)
     .await?;
     Ok(job)
 }
+
+/// Deletes jobs of `job_type` created more than `age` ago and returns their ids.
+pub async fn delete_jobs_older_than(
+    pool: &PgPool,
+    job_type: &str,
+    age: Duration,
+) -> Result<Vec<String>, sqlx::Error> {
+    let ids = sqlx::query_scalar::<_, String>(
+        r#"
+        DELETE FROM jobs
+        WHERE job_type = $1 AND created_at < now() - make_interval(secs => $2)
+        RETURNING id
+        "#
+    )
+    .bind(job_type)
+    .bind(age.as_secs_f64())
+    .fetch_all(pool)
+    .await?;
+    Ok(ids)
+}
+
+pub async fn list_webhook_deliveries(
+    pool: &PgPool,
+    job_id: &str,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        SELECT *
+        FROM webhook_deliveries
+        WHERE job_id = $1
+        ORDER BY created_at ASC
+        "#
+    )
+    .bind(job_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(deliveries)
+}
+
+/// Claims up to `limit` deliveries that are due by pushing their next attempt `lease` into the
+/// future, so other dispatchers skip them while they are being sent.
+pub async fn claim_webhook_deliveries(
+    pool: &PgPool,
+    limit: i64,
+    lease: Duration,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        UPDATE webhook_deliveries
+        SET next_attempt_at = now() + make_interval(secs => $1), updated_at = now()
+        WHERE id IN (
+            SELECT id
+            FROM webhook_deliveries
+            WHERE delivery_status = $2 AND next_attempt_at <= now()
+            ORDER BY next_attempt_at ASC
+            LIMIT $3
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *
+        "#
+    )
+    .bind(lease.as_secs_f64())
+    .bind(DeliveryStatus::Pending)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(deliveries)
+}
+
+pub async fn mark_webhook_delivered(
+    pool: &PgPool,
+    id: i64,
+    response_status: i32,
+) -> Result<Option<WebhookDelivery>, sqlx::Error> {
+    let delivery = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        UPDATE webhook_deliveries
+        SET delivery_status = $1, attempts = attempts + 1, response_status = $2, last_error = NULL,
+            delivered_at = now(), updated_at = now()
+        WHERE id = $3
+        RETURNING *
+        "#
+    )
+    .bind(DeliveryStatus::Delivered)
+    .bind(response_status)
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(delivery)
+}
+
+/// Records a failed attempt. The delivery is retried after `retry_in`, or gives up once it has
+/// been attempted `max_attempts` times.
+pub async fn fail_webhook_attempt(
+    pool: &PgPool,
+    id: i64,
+    response_status: Option<i32>,
+    error: &str,
+    max_attempts: i32,
+    retry_in: Duration,
+) -> Result<Option<WebhookDelivery>, sqlx::Error> {
+    let delivery = sqlx::query_as::<_, WebhookDelivery>(
+        r#"
+        UPDATE webhook_deliveries
+        SET delivery_status = CASE WHEN attempts + 1 < $1 THEN $2 ELSE $3 END,
+            attempts = attempts + 1, response_status = $4, last_error = $5,
+            next_attempt_at = now() + make_interval(secs => $6), updated_at = now()
+        WHERE id = $7
+        RETURNING *
+        "#
+    )
+    .bind(max_attempts)
+    .bind(DeliveryStatus::Pending)
+    .bind(DeliveryStatus::Failed)
+    .bind(response_status)
+    .bind(error)
+    .bind(retry_in.as_secs_f64())
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(delivery)
+}
@@ -0,0 +1,488 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{config, dedup::DuplicateFilter, inference::{ChatMessage, GenerateOptions, GenerateRequest, InferenceBackend}, models::JobError, preprocess::{self, InvalidImage, PreparedImage}};
+
+static IMAGE_EXT_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.(jpg|jpeg|png)").unwrap());
+static IMAGE_ID_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"([a-fA-F0-9]{8}-[a-fA-F0-9]{4}-4[a-fA-F0-9]{3}-[89abAB][a-fA-F0-9]{3}-[a-fA-F0-9]{12})").unwrap()
+});
+static IMAGE_TIMESTAMP_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"_(?P<ts>\d{8}_\d{6})(?:_|\.|$)").unwrap());
+
+#[derive(Deserialize, Debug)]
+pub struct TaskTimingInput {
+    pub vlm_prompt: String,
+    pub prompt: String,
+    pub vlm_model: Option<String>,
+    pub llm_model: Option<String>,
+    /// JSON schema the temporal output must match.
+    pub output_schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TaskTimingOutput {
+    pub logs: String,
+    pub temporal_output: String,
+    /// `temporal_output` parsed and validated against `output_schema`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temporal_json: Option<serde_json::Value>,
+    /// Images the [`DuplicateFilter`](crate::dedup::DuplicateFilter) left out, see its docs
+    /// for what counts as a duplicate.
+    #[serde(default)]
+    pub skipped_images: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VlmOnlyInput {
+    pub vlm_prompt: String,
+    pub vlm_model: Option<String>,
+    /// JSON schema every image response must match.
+    pub output_schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageResponse {
+    pub image_id: String,
+    pub timestamp: String,
+    pub response: String,
+    /// `response` parsed and validated against `output_schema`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_json: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VlmOnlyOutput {
+    pub responses: Vec<ImageResponse>,
+    /// Same as [`TaskTimingOutput::skipped_images`].
+    #[serde(default)]
+    pub skipped_images: u64,
+}
+
+/// Extracts a UUIDv4 image id from the file name, or an empty string if there is none.
+pub fn parse_image_id(image_path: &Path) -> String {
+    let file_name = image_path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    IMAGE_ID_RE
+        .captures(file_name)
+        .map(|c| c[1].to_string())
+        .unwrap_or_default()
+}
+
+/// Extracts a `YYYYMMDD_HHMMSS` capture timestamp from the file name, or an empty string if there is none.
+pub fn parse_image_timestamp(image_path: &Path) -> String {
+    let file_name = image_path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    IMAGE_TIMESTAMP_RE
+        .captures(file_name)
+        .map(|c| c["ts"].to_string())
+        .unwrap_or_default()
+}
+
+/// Lists the images downloaded for a job, sorted by file name.
+/// Files with trailing garbage after the image extension are renamed in place.
+pub async fn find_images(input_dir: &str) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut image_paths = Vec::new();
+    let mut dir = fs::read_dir(input_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let lower_name = file_name.to_lowercase();
+        let Some(m) = IMAGE_EXT_RE.find(&lower_name) else {
+            continue;
+        };
+        let cleaned_name = &file_name[..m.end()];
+        let cleaned_path = Path::new(input_dir).join(cleaned_name);
+        if cleaned_name != file_name {
+            fs::rename(entry.path(), &cleaned_path).await?;
+        }
+        image_paths.push(cleaned_path);
+    }
+    image_paths.sort();
+    Ok(image_paths)
+}
+
+/// Like [`find_images`], but fails the job when there is nothing to run inference on.
+pub async fn load_images(input_dir: &str) -> Result<Vec<PathBuf>, JobError> {
+    let image_paths = find_images(input_dir).await.map_err(|e| JobError {
+        code: "no_images".to_string(),
+        message: e.to_string(),
+    })?;
+    if image_paths.is_empty() {
+        tracing::warn!("No images found: input_dir={}", input_dir);
+        return Err(JobError {
+            code: "no_images".to_string(),
+            message: "No images found".to_string(),
+        });
+    }
+    Ok(image_paths)
+}
+
+/// A response that still did not match the job's `output_schema` after every retry.
+#[derive(Debug)]
+pub struct SchemaMismatch {
+    pub attempts: u32,
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Response did not match the output schema after {} attempts: {}", self.attempts, self.errors.join("; "))
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// The `output_schema` of a job, compiled once and checked against every response.
+pub struct OutputSchema {
+    schema: serde_json::Value,
+    validator: jsonschema::Validator,
+}
+
+impl OutputSchema {
+    pub fn compile(schema: &serde_json::Value) -> Result<Self, String> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+        Ok(OutputSchema {
+            schema: schema.clone(),
+            validator,
+        })
+    }
+
+    fn compile_input(schema: Option<&serde_json::Value>) -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        schema
+            .map(|schema| Self::compile(schema).map_err(|e| format!("Invalid output schema: {}", e).into()))
+            .transpose()
+    }
+
+    /// Parses the response and validates it, returning every problem found otherwise.
+    fn check(&self, response: &str) -> Result<serde_json::Value, Vec<String>> {
+        let value = serde_json::from_str::<serde_json::Value>(response.trim())
+            .map_err(|e| vec![format!("response is not valid JSON: {}", e)])?;
+        let errors: Vec<String> = self.validator
+            .iter_errors(&value)
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect();
+        if errors.is_empty() { Ok(value) } else { Err(errors) }
+    }
+}
+
+/// Runs the request, constrained to `schema` if there is one, until the response matches it
+/// or `retries` more attempts failed.
+async fn generate_validated(
+    backend: &dyn InferenceBackend,
+    mut request: GenerateRequest,
+    schema: Option<&OutputSchema>,
+    retries: u32,
+) -> Result<(String, Option<serde_json::Value>), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(schema) = schema else {
+        return Ok((backend.generate(request).await?, None));
+    };
+    request.format = Some(schema.schema.clone());
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let response = backend.generate(request.clone()).await?;
+        match schema.check(&response) {
+            Ok(value) => return Ok((response, Some(value))),
+            Err(errors) if attempt > retries => return Err(Box::new(SchemaMismatch { attempts: attempt, errors })),
+            Err(errors) => tracing::warn!(attempt, "Response does not match the output schema, retrying: {:?}", errors),
+        }
+    }
+}
+
+/// Like [`generate_validated`] for a chat, telling the model what was wrong before each retry.
+async fn chat_validated(
+    backend: &dyn InferenceBackend,
+    model: &str,
+    prompt: String,
+    schema: Option<&OutputSchema>,
+    retries: u32,
+) -> Result<(String, Option<serde_json::Value>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut messages = vec![ChatMessage::user(prompt)];
+    let Some(schema) = schema else {
+        return Ok((backend.chat(model, messages, None).await?, None));
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let response = backend.chat(model, messages.clone(), Some(&schema.schema)).await?;
+        match schema.check(&response) {
+            Ok(value) => return Ok((response, Some(value))),
+            Err(errors) if attempt > retries => return Err(Box::new(SchemaMismatch { attempts: attempt, errors })),
+            Err(errors) => {
+                tracing::warn!(attempt, "Response does not match the output schema, retrying: {:?}", errors);
+                messages.push(ChatMessage::assistant(response));
+                messages.push(ChatMessage::user(format!(
+                    "Your response does not match the required JSON schema: {}. Respond again with only JSON that matches the schema.",
+                    errors.join("; ")
+                )));
+            }
+        }
+    }
+}
+
+/// Reads and normalizes an image of a job, naming the file if it is not a valid image.
+async fn read_image(image_path: &Path, vlm_config: &config::Config) -> Result<PreparedImage, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = fs::read(image_path).await?;
+    let image = preprocess::prepare(bytes, vlm_config)
+        .await
+        .map_err(|InvalidImage(message)| InvalidImage(format!("{}: {}", image_path.display(), message)))?;
+    Ok(image)
+}
+
+/// Runs the VLM over every image and then asks the LLM to reason about the resulting timeline.
+pub async fn run_task_timing(
+    backend: &dyn InferenceBackend,
+    vlm_config: &config::Config,
+    input: &TaskTimingInput,
+    image_paths: &[PathBuf],
+) -> Result<TaskTimingOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let vlm_model = input.vlm_model.as_ref().unwrap_or(&vlm_config.model);
+    let llm_model = input.llm_model.as_ref().unwrap_or(&vlm_config.llm_model);
+    let output_schema = OutputSchema::compile_input(input.output_schema.as_ref())?;
+    tracing::info!("Running inference: image_count={} vlm_model={} llm_model={}", image_paths.len(), vlm_model, llm_model);
+    let mut results = "id,timestamp,event\n".to_string();
+    let mut duplicates = DuplicateFilter::new(vlm_config.dedup_threshold);
+    for image_path in image_paths {
+        tracing::info!("Processing image: {:?}", image_path);
+        let image = read_image(image_path, vlm_config).await?;
+        if duplicates.is_duplicate(image.dhash) {
+            tracing::info!("Skipping near-duplicate image: {:?}", image_path);
+            continue;
+        }
+        let response = backend.generate(GenerateRequest {
+            model: vlm_model.clone(),
+            prompt: input.vlm_prompt.clone(),
+            images: vec![image.jpeg],
+            options: GenerateOptions::default(),
+            format: None,
+        }).await?;
+        results.push_str(&format!(
+            "\"{}\",\"{}\",\"{}\"\n",
+            parse_image_id(image_path),
+            parse_image_timestamp(image_path),
+            response
+        ));
+    }
+    tracing::info!("Inference completed: skipped_images={}", duplicates.skipped());
+
+    let temporal_prompt = format!(
+        "Given the timeline in the format of id,timestamp,event\nTimeline:{}\n{}",
+        results, input.prompt
+    );
+    let (temporal_output, temporal_json) =
+        chat_validated(backend, llm_model, temporal_prompt, output_schema.as_ref(), vlm_config.output_schema_retries).await?;
+    tracing::info!("Temporal reasoning output: {}", temporal_output);
+
+    Ok(TaskTimingOutput {
+        logs: results,
+        temporal_output,
+        temporal_json,
+        skipped_images: duplicates.skipped(),
+    })
+}
+
+/// Runs the VLM over every image without the LLM temporal reasoning pass.
+pub async fn run_vlm_only(
+    backend: &dyn InferenceBackend,
+    vlm_config: &config::Config,
+    input: &VlmOnlyInput,
+    image_paths: &[PathBuf],
+) -> Result<VlmOnlyOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let vlm_model = input.vlm_model.as_ref().unwrap_or(&vlm_config.model);
+    let output_schema = OutputSchema::compile_input(input.output_schema.as_ref())?;
+    tracing::info!("Running VLM-only inference: image_count={} vlm_model={}", image_paths.len(), vlm_model);
+    let mut responses = Vec::with_capacity(image_paths.len());
+    let mut duplicates = DuplicateFilter::new(vlm_config.dedup_threshold);
+    for image_path in image_paths {
+        tracing::info!("Processing image: {:?}", image_path);
+        let image = read_image(image_path, vlm_config).await?;
+        if duplicates.is_duplicate(image.dhash) {
+            tracing::info!("Skipping near-duplicate image: {:?}", image_path);
+            continue;
+        }
+        let request = GenerateRequest {
+            model: vlm_model.clone(),
+            prompt: input.vlm_prompt.clone(),
+            images: vec![image.jpeg],
+            options: GenerateOptions::default(),
+            format: None,
+        };
+        let (response, response_json) =
+            generate_validated(backend, request, output_schema.as_ref(), vlm_config.output_schema_retries).await?;
+        responses.push(ImageResponse {
+            image_id: parse_image_id(image_path),
+            timestamp: parse_image_timestamp(image_path),
+            response,
+            response_json,
+        });
+    }
+    tracing::info!("VLM-only inference completed: skipped_images={}", duplicates.skipped());
+
+    Ok(VlmOnlyOutput {
+        responses,
+        skipped_images: duplicates.skipped(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::*;
+    use crate::inference::{ChunkStream, Error, PullStream};
+
+    /// Answers with scripted responses and keeps what it was asked.
+    #[derive(Default)]
+    struct ScriptedBackend {
+        responses: Mutex<VecDeque<&'static str>>,
+        formats: Mutex<Vec<Option<serde_json::Value>>>,
+        chats: Mutex<Vec<Vec<ChatMessage>>>,
+    }
+
+    impl ScriptedBackend {
+        fn new(responses: &[&'static str]) -> Self {
+            ScriptedBackend {
+                responses: Mutex::new(responses.iter().copied().collect()),
+                ..Default::default()
+            }
+        }
+
+        fn next(&self) -> Result<String, Error> {
+            let response = self.responses.lock().unwrap().pop_front().expect("no response left");
+            Ok(response.to_string())
+        }
+    }
+
+    #[async_trait]
+    impl InferenceBackend for ScriptedBackend {
+        async fn generate(&self, request: GenerateRequest) -> Result<String, Error> {
+            self.formats.lock().unwrap().push(request.format);
+            self.next()
+        }
+
+        async fn generate_stream(&self, _request: GenerateRequest) -> Result<ChunkStream, Error> {
+            unimplemented!()
+        }
+
+        async fn chat(&self, _model: &str, messages: Vec<ChatMessage>, format: Option<&serde_json::Value>) -> Result<String, Error> {
+            self.formats.lock().unwrap().push(format.cloned());
+            self.chats.lock().unwrap().push(messages);
+            self.next()
+        }
+
+        async fn list_models(&self) -> Result<Vec<String>, Error> {
+            unimplemented!()
+        }
+
+        async fn ensure_model(&self, _model: &str) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        async fn pull_model(&self, _model: &str) -> Result<PullStream, Error> {
+            unimplemented!()
+        }
+
+        async fn delete_model(&self, _model: &str) -> Result<(), Error> {
+            unimplemented!()
+        }
+    }
+
+    fn schema() -> OutputSchema {
+        OutputSchema::compile(&json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer" } },
+            "required": ["count"],
+        })).unwrap()
+    }
+
+    fn request() -> GenerateRequest {
+        GenerateRequest {
+            model: "llava".to_string(),
+            prompt: "Count the people".to_string(),
+            images: Vec::new(),
+            options: GenerateOptions::default(),
+            format: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_validated_returns_valid_response() {
+        let backend = ScriptedBackend::new(&[r#"{"count": 2}"#]);
+        let schema = schema();
+        let (response, value) = generate_validated(&backend, request(), Some(&schema), 2).await.unwrap();
+        assert_eq!(response, r#"{"count": 2}"#);
+        assert_eq!(value, Some(json!({ "count": 2 })));
+        // The schema is passed on to constrain the response
+        assert_eq!(*backend.formats.lock().unwrap(), vec![Some(schema.schema.clone())]);
+    }
+
+    #[tokio::test]
+    async fn generate_validated_retries_invalid_response() {
+        let backend = ScriptedBackend::new(&["two people", r#"{"count": "2"}"#, r#"{"count": 2}"#]);
+        let (_, value) = generate_validated(&backend, request(), Some(&schema()), 2).await.unwrap();
+        assert_eq!(value, Some(json!({ "count": 2 })));
+        assert!(backend.responses.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn generate_validated_fails_after_last_retry() {
+        let backend = ScriptedBackend::new(&["two people", r#"{"count": "2"}"#]);
+        let err = generate_validated(&backend, request(), Some(&schema()), 1).await.unwrap_err();
+        let mismatch = err.downcast_ref::<SchemaMismatch>().unwrap();
+        assert_eq!(mismatch.attempts, 2);
+        assert_eq!(mismatch.errors.len(), 1);
+        assert!(mismatch.errors[0].starts_with("/count: "), "{:?}", mismatch.errors);
+    }
+
+    #[tokio::test]
+    async fn generate_validated_without_schema_returns_any_response() {
+        let backend = ScriptedBackend::new(&["two people"]);
+        let (response, value) = generate_validated(&backend, request(), None, 2).await.unwrap();
+        assert_eq!(response, "two people");
+        assert_eq!(value, None);
+        assert_eq!(*backend.formats.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn chat_validated_tells_model_what_was_wrong() {
+        let backend = ScriptedBackend::new(&["two people", r#"{"count": 2}"#]);
+        let (_, value) = chat_validated(&backend, "llama3", "Count the people".to_string(), Some(&schema()), 1).await.unwrap();
+        assert_eq!(value, Some(json!({ "count": 2 })));
+
+        let chats = backend.chats.lock().unwrap();
+        assert_eq!(chats.len(), 2);
+        let retry = &chats[1];
+        assert_eq!(retry.len(), 3);
+        assert_eq!((retry[1].role.as_str(), retry[1].content.as_str()), ("assistant", "two people"));
+        assert_eq!(retry[2].role, "user");
+        assert!(retry[2].content.contains("response is not valid JSON"), "{}", retry[2].content);
+    }
+
+    #[tokio::test]
+    async fn chat_validated_fails_after_last_retry() {
+        let backend = ScriptedBackend::new(&["two people", "still two people"]);
+        let err = chat_validated(&backend, "llama3", "Count the people".to_string(), Some(&schema()), 1).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<SchemaMismatch>().unwrap().attempts, 2);
+    }
+
+    #[test]
+    fn parse_image_timestamp_finds_capture_time() {
+        assert_eq!(parse_image_timestamp(Path::new("/data/input/frame_20250101_123045.jpg")), "20250101_123045");
+        assert_eq!(parse_image_timestamp(Path::new("cam_20250101_123045_0001.png")), "20250101_123045");
+        assert_eq!(parse_image_timestamp(Path::new("frame_20250101_1230.jpg")), "");
+        assert_eq!(parse_image_timestamp(Path::new("frame.jpg")), "");
+    }
+
+    #[test]
+    fn parse_image_id_finds_uuid() {
+        let id = "3f2b8c1e-9a4d-4e6f-8b7a-1c2d3e4f5a6b";
+        assert_eq!(parse_image_id(Path::new(&format!("/data/input/{}_20250101_123045.jpg", id))), id);
+        assert_eq!(parse_image_id(Path::new("frame_20250101_123045.jpg")), "");
+    }
+}
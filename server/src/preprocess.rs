@@ -0,0 +1,157 @@
+use std::fmt;
+use std::io::Cursor;
+
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+use crate::{config, dedup::dhash};
+
+/// Bytes that are not a JPEG, PNG or WebP image, or that fail to decode.
+#[derive(Debug)]
+pub struct InvalidImage(pub String);
+
+impl fmt::Display for InvalidImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid image: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidImage {}
+
+/// An image ready to be sent to the model.
+pub struct PreparedImage {
+    /// Upright, downscaled JPEG.
+    pub jpeg: Vec<u8>,
+    /// Difference hash of the image, see [`dhash`].
+    pub dhash: u64,
+}
+
+fn decode(bytes: &[u8]) -> Result<DynamicImage, InvalidImage> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| InvalidImage(e.to_string()))?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) => (),
+        Some(format) => return Err(InvalidImage(format!("{:?} is not supported, use JPEG, PNG or WebP", format))),
+        None => return Err(InvalidImage("not a JPEG, PNG or WebP image".to_string())),
+    }
+    let mut decoder = reader.into_decoder().map_err(|e| InvalidImage(e.to_string()))?;
+    // Phones and glasses store rotated images with an EXIF tag instead of rotating the pixels
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| InvalidImage(e.to_string()))?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Decodes the image, turns it upright, shrinks it so that its longest edge is at most
+/// `max_edge` pixels (0 keeps the size) and re-encodes it as a JPEG of the given quality.
+pub fn normalize(bytes: &[u8], max_edge: u32, quality: u8) -> Result<PreparedImage, InvalidImage> {
+    let mut image = decode(bytes)?;
+    if max_edge > 0 && image.width().max(image.height()) > max_edge {
+        image = image.resize(max_edge, max_edge, FilterType::Triangle);
+    }
+    let mut jpeg = Vec::new();
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality))
+        .map_err(|e| InvalidImage(e.to_string()))?;
+    Ok(PreparedImage {
+        jpeg,
+        dhash: dhash(&image),
+    })
+}
+
+/// Runs [`normalize`] with the configured limits off the async runtime.
+pub async fn prepare(bytes: Vec<u8>, vlm_config: &config::Config) -> Result<PreparedImage, InvalidImage> {
+    let (max_edge, quality) = (vlm_config.image_max_edge, vlm_config.image_jpeg_quality);
+    tokio::task::spawn_blocking(move || normalize(&bytes, max_edge, quality))
+        .await
+        .map_err(|e| InvalidImage(format!("image processing failed: {}", e)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    /// Red on the left half, blue on the right half.
+    fn halves(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, _| if x < width / 2 { RED } else { BLUE }))
+    }
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    /// Inserts an EXIF segment with the given orientation right after the JPEG's start marker.
+    fn with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend_from_slice(&[0xff, 0xe1]);
+        bytes.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(&exif);
+        bytes.extend_from_slice(&jpeg[2..]);
+        bytes
+    }
+
+    fn is_close(pixel: Rgb<u8>, expected: Rgb<u8>) -> bool {
+        pixel.0.iter().zip(expected.0).all(|(a, b)| a.abs_diff(b) < 40)
+    }
+
+    #[test]
+    fn normalize_shrinks_longest_edge() {
+        let prepared = normalize(&encode(&halves(200, 100), ImageFormat::Png), 50, 85).unwrap();
+        assert_eq!(image::guess_format(&prepared.jpeg).unwrap(), ImageFormat::Jpeg);
+        let image = decode(&prepared.jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (50, 25));
+    }
+
+    #[test]
+    fn normalize_keeps_small_images_and_disabled_limit() {
+        let small = normalize(&encode(&halves(30, 10), ImageFormat::Jpeg), 50, 85).unwrap();
+        assert_eq!(decode(&small.jpeg).unwrap().width(), 30);
+        let unlimited = normalize(&encode(&halves(200, 100), ImageFormat::Jpeg), 0, 85).unwrap();
+        assert_eq!(decode(&unlimited.jpeg).unwrap().width(), 200);
+    }
+
+    #[test]
+    fn decode_turns_image_upright() {
+        let jpeg = with_orientation(&encode(&halves(40, 20), ImageFormat::Jpeg), 6);
+        let image = decode(&jpeg).unwrap().to_rgb8();
+        // Rotated 90 degrees clockwise, the left half ends up at the top
+        assert_eq!((image.width(), image.height()), (20, 40));
+        assert!(is_close(*image.get_pixel(10, 5), RED), "{:?}", image.get_pixel(10, 5));
+        assert!(is_close(*image.get_pixel(10, 35), BLUE), "{:?}", image.get_pixel(10, 35));
+    }
+
+    #[test]
+    fn normalize_hashes_upright_image() {
+        let upright = halves(40, 20).rotate90();
+        let rotated = with_orientation(&encode(&halves(40, 20), ImageFormat::Jpeg), 6);
+        assert_eq!(normalize(&rotated, 0, 85).unwrap().dhash, dhash(&upright));
+    }
+
+    #[test]
+    fn decode_rejects_unsupported_and_broken_images() {
+        assert!(decode(b"not an image").unwrap_err().0.contains("not a JPEG, PNG or WebP"));
+        assert!(decode(b"GIF89a\x01\x00\x01\x00").unwrap_err().0.contains("not supported"));
+        let jpeg = encode(&halves(40, 20), ImageFormat::Jpeg);
+        assert!(decode(&jpeg[..jpeg.len() / 2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn prepare_uses_configured_limits() {
+        let vlm_config = config::Config { image_max_edge: 64, ..config::Config::for_tests() };
+        let prepared = prepare(encode(&halves(256, 128), ImageFormat::Png), &vlm_config).await.unwrap();
+        let image = decode(&prepared.jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (64, 32));
+        assert!(prepare(b"not an image".to_vec(), &vlm_config).await.is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::{frame::FrameMetadata, inference::image_mime_type, pg};
+
+pub const JOB_TYPE: &str = "session_recording";
+
+/// Entries waiting to be written. Entries recorded while the writer is this far behind are dropped.
+const QUEUE_SIZE: usize = 256;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub struct Config {
+    /// Whether clients may record their sessions with `record=true`.
+    pub enabled: bool,
+    pub max_frames: u64,
+    pub max_bytes: u64,
+    /// How long recordings are kept, `None` keeps them forever.
+    pub retention: Option<Duration>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let retention = std::env::var("RECORDING_RETENTION_HOURS").unwrap_or("168".to_string()).parse::<u64>()?;
+        Ok(Config {
+            enabled: std::env::var("RECORDING_ENABLED").unwrap_or("false".to_string()).parse::<bool>()?,
+            max_frames: std::env::var("RECORDING_MAX_FRAMES").unwrap_or("10000".to_string()).parse::<u64>()?,
+            max_bytes: std::env::var("RECORDING_MAX_MB").unwrap_or("1024".to_string()).parse::<u64>()? * 1024 * 1024,
+            retention: (retention > 0).then(|| Duration::from_secs(retention * 3600)),
+        })
+    }
+}
+
+/// Something that happened in a recorded session, written as one line of `events.jsonl`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// An image received from the client, saved under `frames/`.
+    Frame {
+        frame_id: u64,
+        file: String,
+        bytes: usize,
+        #[serde(flatten)]
+        metadata: FrameMetadata,
+    },
+    /// A frame the session's [`DuplicateFilter`](crate::dedup::DuplicateFilter) left out of batching.
+    FrameSkipped {
+        frame_id: u64,
+    },
+    Prompt {
+        prompt: String,
+    },
+    /// The session options in effect from now on.
+    Options {
+        options: serde_json::Value,
+    },
+    /// Frames sent to the backend together, with the prompt and model they ran with.
+    Batch {
+        batch_id: u64,
+        frames: Vec<u64>,
+        prompt: String,
+        model: String,
+    },
+    Response {
+        batch_id: u64,
+        response: String,
+        timings: serde_json::Value,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        batch_id: Option<u64>,
+        code: String,
+        message: String,
+    },
+    Cancel,
+    /// `RECORDING_MAX_FRAMES` or `RECORDING_MAX_MB` was reached, no more frames are saved.
+    Truncated,
+}
+
+#[derive(Serialize)]
+struct EventLine<'a> {
+    at: DateTime<Utc>,
+    /// Milliseconds since the session started.
+    offset_ms: i64,
+    #[serde(flatten)]
+    event: &'a SessionEvent,
+}
+
+enum Entry {
+    Frame {
+        frame_id: u64,
+        metadata: FrameMetadata,
+        data: Bytes,
+        at: DateTime<Utc>,
+    },
+    Event {
+        event: SessionEvent,
+        at: DateTime<Utc>,
+    },
+}
+
+/// Stored as the `output` of the `session_recording` job.
+#[derive(Serialize, Debug)]
+pub struct RecordingSummary {
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub frames: u64,
+    pub bytes: u64,
+    pub batches: u64,
+    pub responses: u64,
+    /// Frames were left out because the recording reached its size limits.
+    pub truncated: bool,
+    /// Frames and events left out because the disk could not keep up.
+    pub dropped: u64,
+}
+
+/// Hands events to the writer of a recording. Cheap to clone, events are timestamped when
+/// they are recorded, not when they are written. Never waits for the writer, entries that
+/// don't fit in its queue are dropped and counted instead.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::Sender<Entry>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Recorder {
+    pub fn frame(&self, frame_id: u64, metadata: FrameMetadata, data: Bytes) {
+        self.send(Entry::Frame { frame_id, metadata, data, at: Utc::now() });
+    }
+
+    pub fn event(&self, event: SessionEvent) {
+        self.send(Entry::Event { event, at: Utc::now() });
+    }
+
+    fn send(&self, entry: Entry) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(entry) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Limits {
+    max_frames: u64,
+    max_bytes: u64,
+}
+
+/// A WebSocket session being saved to `DATA_DIR/sessions/{id}`: every frame under `frames/`
+/// and everything else in `events.jsonl`.
+pub struct Recording {
+    pub id: String,
+    pub dir: String,
+    recorder: Recorder,
+    writer: JoinHandle<io::Result<RecordingSummary>>,
+}
+
+pub fn session_dir(data_dir: &str, id: &str) -> String {
+    format!("{}/sessions/{}", data_dir, id)
+}
+
+impl Recording {
+    pub async fn start(data_dir: &str, id: String, config: &Config) -> io::Result<Self> {
+        let dir = session_dir(data_dir, &id);
+        let events = match create_files(&dir).await {
+            Ok(events) => events,
+            Err(e) => {
+                discard(&dir).await;
+                return Err(e);
+            }
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let limits = Limits { max_frames: config.max_frames, max_bytes: config.max_bytes };
+        let writer = tokio::spawn(write_entries(dir.clone(), events, rx, limits));
+        Ok(Recording {
+            id,
+            dir,
+            recorder: Recorder { tx, dropped },
+            writer,
+        })
+    }
+
+    pub fn recorder(&self) -> Recorder {
+        self.recorder.clone()
+    }
+
+    /// Waits for every recorder to be dropped and the recording to be written, then creates
+    /// a completed `session_recording` job pointing at it. Recordings that fail to be written
+    /// or saved are deleted, since retention only finds recordings through their job.
+    pub async fn finish(self, pool: &PgPool, domain_id: Option<&str>) {
+        let Recording { id, dir, recorder, writer } = self;
+        let dropped = recorder.dropped.clone();
+        drop(recorder);
+        let summary = match writer.await {
+            Ok(Ok(summary)) => RecordingSummary { dropped: dropped.load(Ordering::Relaxed), ..summary },
+            Ok(Err(e)) => {
+                tracing::error!(session_id = %id, "Failed to write session recording: {:?}", e);
+                discard(&dir).await;
+                return;
+            }
+            Err(e) => {
+                tracing::error!(session_id = %id, "Session recorder panicked: {:?}", e);
+                discard(&dir).await;
+                return;
+            }
+        };
+        let input = json!({
+            "session_dir": dir,
+            "events": "events.jsonl",
+        });
+        let output = json!(summary);
+        match pg::create_completed_job(pool, &id, domain_id, &json!({}), &input, &output, JOB_TYPE).await {
+            Ok(_) => tracing::info!(session_id = %id, frames = summary.frames, "Session recording saved"),
+            Err(e) => {
+                tracing::error!(session_id = %id, "Failed to create session recording job: {:?}", e);
+                discard(&dir).await;
+            }
+        }
+    }
+}
+
+async fn create_files(dir: &str) -> io::Result<tokio::fs::File> {
+    tokio::fs::create_dir_all(format!("{}/frames", dir)).await?;
+    tokio::fs::File::create(format!("{}/events.jsonl", dir)).await
+}
+
+async fn discard(dir: &str) {
+    match tokio::fs::remove_dir_all(dir).await {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => tracing::warn!("Failed to delete folder {}: {:?}", dir, e),
+    }
+}
+
+fn frame_file(frame_id: u64, data: &[u8]) -> String {
+    let extension = match image_mime_type(data) {
+        "image/png" => "png",
+        "image/webp" => "webp",
+        _ => "jpg",
+    };
+    format!("frames/{:06}.{}", frame_id, extension)
+}
+
+async fn write_event(events: &mut tokio::fs::File, started_at: DateTime<Utc>, at: DateTime<Utc>, event: &SessionEvent) -> io::Result<()> {
+    let line = EventLine {
+        at,
+        offset_ms: (at - started_at).num_milliseconds(),
+        event,
+    };
+    let mut line = serde_json::to_vec(&line)?;
+    line.push(b'\n');
+    events.write_all(&line).await
+}
+
+async fn write_entries(dir: String, mut events: tokio::fs::File, mut rx: mpsc::Receiver<Entry>, limits: Limits) -> io::Result<RecordingSummary> {
+    let started_at = Utc::now();
+    let mut summary = RecordingSummary {
+        started_at,
+        ended_at: started_at,
+        frames: 0,
+        bytes: 0,
+        batches: 0,
+        responses: 0,
+        truncated: false,
+        dropped: 0,
+    };
+    while let Some(entry) = rx.recv().await {
+        match entry {
+            Entry::Frame { frame_id, metadata, data, at } => {
+                if summary.frames >= limits.max_frames || summary.bytes + data.len() as u64 > limits.max_bytes {
+                    if !summary.truncated {
+                        summary.truncated = true;
+                        write_event(&mut events, started_at, at, &SessionEvent::Truncated).await?;
+                    }
+                    continue;
+                }
+                let file = frame_file(frame_id, &data);
+                tokio::fs::write(format!("{}/{}", dir, file), &data).await?;
+                summary.frames += 1;
+                summary.bytes += data.len() as u64;
+                let event = SessionEvent::Frame { frame_id, file, bytes: data.len(), metadata };
+                write_event(&mut events, started_at, at, &event).await?;
+            }
+            Entry::Event { event, at } => {
+                match event {
+                    SessionEvent::Batch { .. } => summary.batches += 1,
+                    SessionEvent::Response { .. } => summary.responses += 1,
+                    _ => (),
+                }
+                write_event(&mut events, started_at, at, &event).await?;
+            }
+        }
+    }
+    events.flush().await?;
+    summary.ended_at = Utc::now();
+    Ok(summary)
+}
+
+/// Deletes recordings older than `retention` with their `session_recording` job, every hour.
+pub fn spawn_cleanup(pool: PgPool, data_dir: String, retention: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match pg::delete_jobs_older_than(&pool, JOB_TYPE, retention).await {
+                Ok(ids) => {
+                    for id in ids {
+                        let dir = session_dir(&data_dir, &id);
+                        match tokio::fs::remove_dir_all(&dir).await {
+                            Ok(()) => tracing::info!(session_id = %id, "Deleted expired session recording"),
+                            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                            Err(e) => tracing::warn!("Failed to delete folder {}: {:?}", dir, e),
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("Failed to delete expired session recordings: {:?}", e),
+            }
+        }
+    });
+}
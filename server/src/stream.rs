@@ -1,117 +1,699 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
 use actix_web::{rt, web, Error, HttpRequest, HttpResponse};
 use actix_ws::Message;
+use bytes::Bytes;
 use futures::{select, FutureExt};
 use futures_util::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::{self, Duration, Instant};
 
-use crate::{config, ollama_client::{send_to_ollama}};
+use crate::{config::{self, OverflowPolicy}, dedup::DuplicateFilter, inference::{GenerateOptions, GenerateRequest, InferenceBackend}, frame::{parse_frame, FrameMetadata}, metrics::METRICS, preprocess, recording::{self, Recorder, Recording, SessionEvent}};
 
-async fn proxy_ollama_response(
-    session: &mut actix_ws::Session,
-    images_batch: Vec<Vec<u8>>,
-    prompt: String,
-    model: String,
-    ollama_host: String,
+/// Latest version of the typed JSON protocol. Version 0 is the original protocol where text
+/// frames are prompts and responses are raw `{"response", "done"}` binary frames.
+const PROTOCOL_VERSION: u32 = 1;
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Keeps idle connections open through proxies, whatever flush interval the session uses.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const FLUSH_INTERVAL_RANGE: (u64, u64) = (1_000, 300_000);
+const MAX_BATCH_SIZE: usize = 32;
+const NUM_PREDICT_RANGE: (i32, i32) = (1, 4096);
+/// Ollama's `num_predict` for generating until the model stops on its own.
+const NUM_PREDICT_UNLIMITED: i32 = -1;
+const NUM_CTX_RANGE: (u32, u32) = (512, 32768);
+const MAX_STOP_SEQUENCES: usize = 4;
+
+#[derive(Deserialize, Debug)]
+struct WsQuery {
+    #[serde(default)]
+    protocol: u32,
+    /// Save the session under `DATA_DIR/sessions/{id}` and create a `session_recording` job on close.
+    #[serde(default)]
+    record: bool,
+    /// Domain the recording belongs to.
+    domain_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Options a session can set in the handshake query or with a `set_options` message.
+/// Unset options keep their current value.
+#[derive(Deserialize, Debug, Default)]
+struct SessionOptions {
+    model: Option<String>,
     num_predict: Option<i32>,
-) {
-    let mut session_clone = session.clone();
-    rt::spawn(async move {
-        match send_to_ollama(images_batch, prompt, model, ollama_host, num_predict).await {
-            Ok(mut rx) => {
-                while let Some(res) = rx.next().await {
-                    match res {
-                        Ok(bytes) => {
-                            if let Err(e) = session_clone.binary(bytes).await {
-                                tracing::error!("Error: {:?}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!("Error: {:?}", e);
-                            break;
-                        }
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<i64>,
+    num_ctx: Option<u32>,
+    stop: Option<StopSequences>,
+    format: Option<serde_json::Value>,
+    batch_size: Option<usize>,
+    flush_interval_ms: Option<u64>,
+}
+
+/// The options a session currently runs with, echoed back to the client after every change.
+#[derive(Serialize, Debug, Clone)]
+struct EffectiveOptions {
+    model: String,
+    batch_size: usize,
+    flush_interval_ms: u64,
+    #[serde(flatten)]
+    generate: GenerateOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+impl EffectiveOptions {
+    fn defaults(vlm_config: &config::Config) -> Self {
+        EffectiveOptions {
+            model: vlm_config.model.clone(),
+            batch_size: vlm_config.image_batch_size,
+            flush_interval_ms: DEFAULT_FLUSH_INTERVAL.as_millis() as u64,
+            generate: GenerateOptions::default(),
+            format: None,
+        }
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+
+    /// Returns these options with `changes` applied. Numbers are clamped to their limits,
+    /// values that can't be clamped are rejected with an error code and message.
+    fn apply(&self, changes: SessionOptions, vlm_config: &config::Config) -> Result<Self, (&'static str, String)> {
+        let mut options = self.clone();
+        if let Some(model) = changes.model {
+            if !vlm_config.is_model_allowed(&model) {
+                return Err(("model_not_allowed", format!("Model {} is not allowed", model)));
+            }
+            options.model = model;
+        }
+        for (name, value) in [("temperature", changes.temperature), ("top_p", changes.top_p)] {
+            if value.is_some_and(|v| !v.is_finite()) {
+                return Err(("invalid_options", format!("{} must be a finite number", name)));
+            }
+        }
+        if let Some(temperature) = changes.temperature {
+            options.generate.temperature = Some(temperature.clamp(0.0, 2.0));
+        }
+        if let Some(top_p) = changes.top_p {
+            options.generate.top_p = Some(top_p.clamp(0.0, 1.0));
+        }
+        if let Some(num_predict) = changes.num_predict {
+            options.generate.num_predict = Some(if num_predict == NUM_PREDICT_UNLIMITED {
+                NUM_PREDICT_UNLIMITED
+            } else {
+                num_predict.clamp(NUM_PREDICT_RANGE.0, NUM_PREDICT_RANGE.1)
+            });
+        }
+        if let Some(num_ctx) = changes.num_ctx {
+            options.generate.num_ctx = Some(num_ctx.clamp(NUM_CTX_RANGE.0, NUM_CTX_RANGE.1));
+        }
+        if let Some(seed) = changes.seed {
+            options.generate.seed = Some(seed);
+        }
+        if let Some(stop) = changes.stop {
+            let stop = match stop {
+                StopSequences::One(stop) => vec![stop],
+                StopSequences::Many(stop) => stop,
+            };
+            if stop.len() > MAX_STOP_SEQUENCES || stop.iter().any(|s| s.is_empty()) {
+                return Err(("invalid_options", format!("stop takes up to {} non-empty sequences", MAX_STOP_SEQUENCES)));
+            }
+            options.generate.stop = stop;
+        }
+        if let Some(format) = changes.format {
+            options.format = match format {
+                serde_json::Value::Null => None,
+                serde_json::Value::String(s) if s.is_empty() => None,
+                serde_json::Value::String(s) if s == "json" => Some(serde_json::Value::String(s)),
+                serde_json::Value::Object(schema) => Some(serde_json::Value::Object(schema)),
+                _ => return Err(("invalid_options", "format must be \"json\" or a JSON schema object".to_string())),
+            };
+        }
+        if let Some(batch_size) = changes.batch_size {
+            options.batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+        }
+        if let Some(flush_interval_ms) = changes.flush_interval_ms {
+            options.flush_interval_ms = flush_interval_ms.clamp(FLUSH_INTERVAL_RANGE.0, FLUSH_INTERVAL_RANGE.1);
+        }
+        Ok(options)
+    }
+}
+
+/// Messages sent by the client as text frames. Images are sent as binary frames.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Sets the prompt used for the next batches.
+    SetPrompt { prompt: String },
+    SetOptions(SessionOptions),
+    /// Sends the buffered images without waiting for a full batch.
+    Flush,
+    /// Drops the buffered images and stops the batches that are running.
+    Cancel,
+}
+
+/// A frame of a batch, with the metadata of its envelope.
+#[derive(Serialize, Debug, Clone)]
+struct FrameInfo {
+    frame_id: u64,
+    #[serde(flatten)]
+    metadata: FrameMetadata,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchTimings {
+    /// Time until the first token arrived.
+    first_token_ms: Option<u64>,
+    total_ms: u64,
+    /// Number of streamed chunks, one token each for Ollama.
+    tokens: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Hello {
+        protocol: u32,
+        options: EffectiveOptions,
+        /// Id of the `session_recording` job created when a recorded session closes.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
+    /// Sent after `set_options` with the options now in effect.
+    Options(EffectiveOptions),
+    BatchStarted {
+        batch_id: u64,
+        images: usize,
+        model: String,
+    },
+    Token {
+        batch_id: u64,
+        text: String,
+    },
+    BatchDone {
+        batch_id: u64,
+        response: String,
+        /// The frames of the batch in the order they were sent to the model.
+        frames: Vec<FrameInfo>,
+        /// Near-duplicate frames left out since the previous batch.
+        skipped_frames: u64,
+        timings: BatchTimings,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        batch_id: Option<u64>,
+    },
+}
+
+impl ServerMessage {
+    fn error(code: &'static str, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+            batch_id: None,
+        }
+    }
+
+    fn batch_error(batch_id: u64, code: &'static str, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+            batch_id: Some(batch_id),
+        }
+    }
+}
+
+/// Sends server messages encoded for the protocol version the client connected with, and
+/// records responses and errors of recorded sessions.
+#[derive(Clone)]
+struct Outbox {
+    session: actix_ws::Session,
+    protocol: u32,
+    recorder: Option<Recorder>,
+}
+
+impl Outbox {
+    fn record(&self, message: &ServerMessage) {
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+        match message {
+            ServerMessage::BatchDone { batch_id, response, timings, .. } => recorder.event(SessionEvent::Response {
+                batch_id: *batch_id,
+                response: response.clone(),
+                timings: json!(timings),
+            }),
+            ServerMessage::Error { code, message, batch_id } => recorder.event(SessionEvent::Error {
+                batch_id: *batch_id,
+                code: code.to_string(),
+                message: message.clone(),
+            }),
+            _ => (),
+        }
+    }
+
+    async fn send(&mut self, message: &ServerMessage) -> Result<(), actix_ws::Closed> {
+        self.record(message);
+        if self.protocol >= 1 {
+            return self.session.text(serde_json::to_string(message).unwrap_or_default()).await;
+        }
+        match message {
+            ServerMessage::Token { text, .. } => {
+                self.session.binary(json!({ "response": text, "done": false }).to_string()).await
+            }
+            ServerMessage::BatchDone { .. } => {
+                self.session.binary(json!({ "response": "", "done": true }).to_string()).await
+            }
+            ServerMessage::Error { message, .. } => self.session.text(message.clone()).await,
+            ServerMessage::Hello { .. } | ServerMessage::Options(_) | ServerMessage::BatchStarted { .. } => Ok(()),
+        }
+    }
+}
+
+/// Streams one batch through the backend, reporting its progress to the client.
+async fn run_batch(mut outbox: Outbox, backend: Arc<dyn InferenceBackend>, batch: Batch) {
+    let Batch { id: batch_id, frames, skipped_frames, request } = batch;
+    let started = Instant::now();
+    let batch_started = ServerMessage::BatchStarted {
+        batch_id,
+        images: request.images.len(),
+        model: request.model.clone(),
+    };
+    if outbox.send(&batch_started).await.is_err() {
+        return;
+    }
+
+    let mut chunks = match backend.generate_stream(request).await {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            tracing::error!("Error: {:?}", e);
+            let _ = outbox.send(&ServerMessage::batch_error(batch_id, "inference_error", e.to_string())).await;
+            return;
+        }
+    };
+
+    let mut response = String::new();
+    let mut first_token = None;
+    let mut tokens = 0;
+    while let Some(res) = chunks.next().await {
+        match res {
+            Ok(chunk) => {
+                if !chunk.response.is_empty() {
+                    first_token.get_or_insert_with(|| started.elapsed());
+                    tokens += 1;
+                    response.push_str(&chunk.response);
+                    if let Err(e) = outbox.send(&ServerMessage::Token { batch_id, text: chunk.response }).await {
+                        tracing::error!("Error: {:?}", e);
+                        return;
                     }
                 }
+                if chunk.done {
+                    break;
+                }
             }
             Err(e) => {
                 tracing::error!("Error: {:?}", e);
+                let _ = outbox.send(&ServerMessage::batch_error(batch_id, "inference_error", e.to_string())).await;
+                return;
             }
         }
-    });
+    }
+
+    let timings = BatchTimings {
+        first_token_ms: first_token.map(|t| t.as_millis() as u64),
+        total_ms: started.elapsed().as_millis() as u64,
+        tokens,
+    };
+    let _ = outbox.send(&ServerMessage::BatchDone { batch_id, response, frames, skipped_frames, timings }).await;
 }
 
-async fn handle_binary(
-    images: &mut Vec<Vec<u8>>,
-    bin: bytes::Bytes,
-    session: &mut actix_ws::Session,
-    last_prompt: &Option<String>,
-    model: String,
-    ollama_host: String,
-    image_batch_size: usize,
-    num_predict: Option<i32>,
-) {
-    images.push(bin.to_vec());
+struct Frame {
+    id: u64,
+    metadata: FrameMetadata,
+    data: Vec<u8>,
+}
+
+struct Batch {
+    id: u64,
+    frames: Vec<FrameInfo>,
+    skipped_frames: u64,
+    request: GenerateRequest,
+}
 
-    if images.len() >= image_batch_size {
-        if let Some(prompt) = last_prompt.clone() {
-            let images_batch = std::mem::take(images);
-            proxy_ollama_response(session, images_batch, prompt, model, ollama_host, num_predict).await;
-        } else {
-            let _ = session.text("No prompt received for image batch".to_string()).await;
+struct RunningBatch {
+    id: u64,
+    frames: usize,
+    handle: JoinHandle<()>,
+}
+
+struct StreamSession {
+    outbox: Outbox,
+    backend: Arc<dyn InferenceBackend>,
+    vlm_config: web::Data<config::Config>,
+    options: EffectiveOptions,
+    prompt: Option<String>,
+    images: Vec<Frame>,
+    next_frame_id: u64,
+    duplicates: DuplicateFilter,
+    /// Value of `duplicates.skipped()` when the last batch was made.
+    skipped_before_batch: u64,
+    next_batch_id: u64,
+    /// Batches being generated, oldest first. Aborting one drops its request to the backend.
+    running: VecDeque<RunningBatch>,
+    queued: VecDeque<Batch>,
+    /// Receives the id of every batch that ran to completion.
+    finished_tx: mpsc::UnboundedSender<u64>,
+}
+
+impl StreamSession {
+    async fn send(&mut self, message: ServerMessage) {
+        if let Err(e) = self.outbox.send(&message).await {
+            tracing::error!("Error: {:?}", e);
         }
     }
-}
 
-async fn handle_text(
-    session: &mut actix_ws::Session,
-    images: &mut Vec<Vec<u8>>,
-    text: String,
-    last_prompt: &mut Option<String>,
-    model: String,
-    ollama_host: String,
-    num_predict: Option<i32>,
-) {
-    *last_prompt = Some(text.clone());
+    fn record(&self, event: SessionEvent) {
+        if let Some(recorder) = &self.outbox.recorder {
+            recorder.event(event);
+        }
+    }
+
+    async fn handle_binary(&mut self, bin: Bytes) {
+        METRICS.ws_frames_received.inc();
+        let (metadata, image) = match parse_frame(bin) {
+            Ok(frame) => frame,
+            Err(message) => {
+                drop_frames("invalid", 1);
+                self.send(ServerMessage::error("invalid_frame", message)).await;
+                return;
+            }
+        };
+        let id = self.next_frame_id;
+        self.next_frame_id += 1;
+        if let Some(recorder) = &self.outbox.recorder {
+            recorder.frame(id, metadata.clone(), image.clone());
+        }
+        let image = match preprocess::prepare(image.to_vec(), &self.vlm_config).await {
+            Ok(image) => image,
+            Err(e) => {
+                drop_frames("invalid", 1);
+                self.send(ServerMessage::error("invalid_image", format!("Frame {}: {}", id, e))).await;
+                return;
+            }
+        };
+        if self.duplicates.is_duplicate(image.dhash) {
+            drop_frames("duplicate", 1);
+            self.record(SessionEvent::FrameSkipped { frame_id: id });
+            return;
+        }
+        self.images.push(Frame { id, metadata, data: image.jpeg });
+
+        if self.images.len() >= self.options.batch_size {
+            if self.prompt.is_some() {
+                self.flush().await;
+            } else {
+                self.send(ServerMessage::error("no_prompt", "No prompt received for image batch")).await;
+            }
+        }
+    }
 
-    // If prompt updated, send the images to Ollama
-    if !images.is_empty() {
-        let images_batch = std::mem::take(images);
-        proxy_ollama_response(session, images_batch, text, model, ollama_host, num_predict).await;
+    async fn handle_text(&mut self, text: String) {
+        let message = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => message,
+            // Version 0 clients send the prompt as plain text
+            Err(_) if self.outbox.protocol == 0 => ClientMessage::SetPrompt { prompt: text },
+            Err(e) => {
+                self.send(ServerMessage::error("invalid_message", e.to_string())).await;
+                return;
+            }
+        };
+
+        match message {
+            ClientMessage::SetPrompt { prompt } => {
+                // Responses to the previous prompt are of no use anymore
+                if self.prompt.as_ref().is_some_and(|previous| *previous != prompt) {
+                    self.stop_batches("cancelled", "The prompt changed").await;
+                }
+                self.record(SessionEvent::Prompt { prompt: prompt.clone() });
+                self.prompt = Some(prompt);
+                // If prompt updated, send the images to the inference backend
+                self.flush().await;
+            }
+            ClientMessage::SetOptions(changes) => match self.options.apply(changes, &self.vlm_config) {
+                Ok(options) => {
+                    tracing::info!("Session options changed: {:?}", options);
+                    self.options = options;
+                    self.record(SessionEvent::Options { options: json!(self.options) });
+                    self.send(ServerMessage::Options(self.options.clone())).await;
+                }
+                Err((code, message)) => self.send(ServerMessage::error(code, message)).await,
+            },
+            ClientMessage::Flush => {
+                if self.prompt.is_none() && !self.images.is_empty() {
+                    self.send(ServerMessage::error("no_prompt", "No prompt received for image batch")).await;
+                    return;
+                }
+                self.flush().await;
+            }
+            ClientMessage::Cancel => {
+                self.record(SessionEvent::Cancel);
+                self.images.clear();
+                self.stop_batches("cancelled", "Cancelled by the client").await;
+            }
+        }
+    }
+
+    /// Schedules a batch with the buffered images, if there are any and a prompt is set.
+    async fn flush(&mut self) {
+        let Some(prompt) = self.prompt.clone() else {
+            return;
+        };
+        if self.images.is_empty() {
+            return;
+        }
+        tracing::info!("Sending images to inference backend: {:?}", self.images.len());
+        let frames = std::mem::take(&mut self.images);
+        METRICS.ws_frames_batched.inc_by(frames.len() as u64);
+        let batch_id = self.next_batch_id;
+        self.next_batch_id += 1;
+        self.record(SessionEvent::Batch {
+            batch_id,
+            frames: frames.iter().map(|frame| frame.id).collect(),
+            prompt: prompt.clone(),
+            model: self.options.model.clone(),
+        });
+        let (frames, images) = frames
+            .into_iter()
+            .map(|frame| (FrameInfo { frame_id: frame.id, metadata: frame.metadata }, frame.data))
+            .unzip();
+        let request = GenerateRequest {
+            model: self.options.model.clone(),
+            prompt,
+            images,
+            options: self.options.generate.clone(),
+            format: self.options.format.clone(),
+        };
+        let batch = Batch {
+            id: batch_id,
+            frames,
+            skipped_frames: self.duplicates.skipped() - self.skipped_before_batch,
+            request,
+        };
+        self.skipped_before_batch = self.duplicates.skipped();
+
+        if self.running.len() < self.vlm_config.max_in_flight_batches {
+            self.start(batch);
+            return;
+        }
+        match self.vlm_config.overflow_policy {
+            OverflowPolicy::Queue if self.queued.len() < self.vlm_config.max_queued_batches => {
+                self.queued.push_back(batch);
+            }
+            OverflowPolicy::Queue => {
+                drop_frames("overflow", batch.frames.len());
+                self.send(ServerMessage::batch_error(batch.id, "queue_full", "Too many batches waiting, dropping this one")).await;
+            }
+            OverflowPolicy::DropOldest => {
+                if let Some(oldest) = self.running.pop_front() {
+                    oldest.handle.abort();
+                    drop_frames("overflow", oldest.frames);
+                    self.send(ServerMessage::batch_error(oldest.id, "batch_dropped", "Dropped to make room for a newer batch")).await;
+                }
+                self.start(batch);
+            }
+            OverflowPolicy::Reject => {
+                drop_frames("overflow", batch.frames.len());
+                self.send(ServerMessage::batch_error(batch.id, "busy", "Too many batches running, dropping this one")).await;
+            }
+        }
+    }
+
+    fn start(&mut self, batch: Batch) {
+        let outbox = self.outbox.clone();
+        let backend = self.backend.clone();
+        let finished_tx = self.finished_tx.clone();
+        let batch_id = batch.id;
+        let frames = batch.frames.len();
+        let handle = rt::spawn(async move {
+            run_batch(outbox, backend, batch).await;
+            let _ = finished_tx.send(batch_id);
+        });
+        self.running.push_back(RunningBatch { id: batch_id, frames, handle });
+    }
+
+    /// Starts queued batches as running ones finish.
+    fn batch_finished(&mut self, batch_id: u64) {
+        self.running.retain(|batch| batch.id != batch_id);
+        while self.running.len() < self.vlm_config.max_in_flight_batches {
+            let Some(batch) = self.queued.pop_front() else {
+                break;
+            };
+            self.start(batch);
+        }
+    }
+
+    /// Aborts the running batches and drops the queued ones, telling the client about each of them.
+    async fn stop_batches(&mut self, code: &'static str, message: &str) {
+        let running: Vec<u64> = self.running.drain(..).map(|batch| {
+            batch.handle.abort();
+            drop_frames("cancelled", batch.frames);
+            batch.id
+        }).collect();
+        let queued: Vec<u64> = self.queued.drain(..).map(|batch| {
+            drop_frames("cancelled", batch.frames.len());
+            batch.id
+        }).collect();
+        if !running.is_empty() || !queued.is_empty() {
+            tracing::info!("Stopped {} running and {} queued batches: {}", running.len(), queued.len(), message);
+        }
+        for batch_id in running.into_iter().chain(queued) {
+            self.send(ServerMessage::batch_error(batch_id, code, message)).await;
+        }
+    }
+
+    /// Stops all work of a session whose socket is gone.
+    fn abort(&mut self) {
+        for batch in self.running.drain(..) {
+            batch.handle.abort();
+            drop_frames("closed", batch.frames);
+        }
+        for batch in self.queued.drain(..) {
+            drop_frames("closed", batch.frames.len());
+        }
+        drop_frames("closed", self.images.len());
     }
 }
 
+fn drop_frames(reason: &str, frames: usize) {
+    METRICS.ws_frames_dropped.with_label_values(&[reason]).inc_by(frames as u64);
+}
+
 async fn handle_ping(session: &mut actix_ws::Session, msg: bytes::Bytes) {
     let _ = session.pong(&msg).await;
 }
 
-pub async fn ws_index(req: HttpRequest, stream: web::Payload, vlm_config: web::Data<config::Config>) -> Result<HttpResponse, Error> {
-    let (res, mut session, stream) = actix_ws::handle(&req, stream)?;
+pub async fn ws_index(
+    req: HttpRequest,
+    stream: web::Payload,
+    vlm_config: web::Data<config::Config>,
+    backend: web::Data<dyn InferenceBackend>,
+    pool: web::Data<sqlx::PgPool>,
+    data_dir: web::Data<String>,
+    recording_config: web::Data<recording::Config>,
+) -> Result<HttpResponse, Error> {
+    let query = match web::Query::<WsQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    if query.protocol > PROTOCOL_VERSION {
+        return Ok(HttpResponse::BadRequest().body(format!("Unsupported protocol version {}, latest is {}", query.protocol, PROTOCOL_VERSION)));
+    }
+    let changes = match web::Query::<SessionOptions>::from_query(req.query_string()) {
+        Ok(changes) => changes.into_inner(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let options = match EffectiveOptions::defaults(&vlm_config).apply(changes, &vlm_config) {
+        Ok(options) => options,
+        Err((_, message)) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
 
-    let ollama_host = vlm_config.ollama_host.clone();
-    let model = vlm_config.model.clone();
-    let image_batch_size = vlm_config.image_batch_size;
+    if query.record && !recording_config.enabled {
+        return Ok(HttpResponse::Forbidden().body("Session recording is disabled"));
+    }
 
-    // Parse num_predict from query parameters
-    let num_predict = req
-        .query_string()
-        .split('&')
-        .find_map(|param| {
-            let mut parts = param.split('=');
-            if parts.next()? == "num_predict" {
-                parts.next()?.parse::<i32>().ok()
-            } else {
-                None
+    let (res, session, stream) = actix_ws::handle(&req, stream)?;
+
+    // Started once the handshake is accepted, so that no recording is left without its session
+    let recording = if query.record {
+        match Recording::start(&data_dir, uuid::Uuid::new_v4().to_string(), &recording_config).await {
+            Ok(recording) => Some(recording),
+            Err(e) => {
+                tracing::error!("Failed to start session recording: {:?}", e);
+                return Ok(HttpResponse::InternalServerError().body("Failed to start session recording"));
             }
-        });
+        }
+    } else {
+        None
+    };
 
     let mut stream = stream.max_frame_size(1024*1024);
+    let (finished_tx, mut finished_rx) = mpsc::unbounded_channel();
+
+    let duplicates = DuplicateFilter::new(vlm_config.dedup_threshold);
+    let mut ws = StreamSession {
+        outbox: Outbox {
+            session,
+            protocol: query.protocol,
+            recorder: recording.as_ref().map(|recording| recording.recorder()),
+        },
+        backend: backend.into_inner(),
+        vlm_config,
+        options,
+        prompt: None,
+        images: Vec::new(),
+        next_frame_id: 0,
+        duplicates,
+        skipped_before_batch: 0,
+        next_batch_id: 0,
+        running: VecDeque::new(),
+        queued: VecDeque::new(),
+        finished_tx,
+    };
 
     rt::spawn(async move {
-        let mut images: Vec<Vec<u8>> = Vec::new();
-        let mut last_prompt: Option<String> = None;
+        METRICS.ws_sessions.inc();
+        if let Some(recording) = &recording {
+            tracing::info!(session_id = %recording.id, "Recording session to {}", recording.dir);
+        }
+        ws.record(SessionEvent::Options { options: json!(ws.options) });
+        let hello = ServerMessage::Hello {
+            protocol: ws.outbox.protocol,
+            options: ws.options.clone(),
+            session_id: recording.as_ref().map(|recording| recording.id.clone()),
+        };
+        ws.send(hello).await;
 
-        let mut inference_interval = time::interval_at(Instant::now() + Duration::from_secs(30), Duration::from_secs(10));
+        let mut flush_interval = ws.options.flush_interval();
+        let mut inference_interval = time::interval_at(Instant::now() + flush_interval, flush_interval);
         inference_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+        let mut ping_interval = time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        ping_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
         loop {
             select! {
@@ -120,12 +702,17 @@ pub async fn ws_index(req: HttpRequest, stream: web::Payload, vlm_config: web::D
                         Some(Ok(Message::Binary(bin))) => {
                             tracing::info!("Received binary message: {:?}", bin.len());
                             inference_interval.reset();
-                            handle_binary(&mut images, bin, &mut session, &last_prompt, model.clone(), ollama_host.clone(), image_batch_size, num_predict).await;
+                            ws.handle_binary(bin).await;
                         }
                         Some(Ok(Message::Text(text))) => {
                             tracing::info!("Received text message: {:?}", text);
                             inference_interval.reset();
-                            handle_text(&mut session, &mut images, text.to_string(), &mut last_prompt, model.clone(), ollama_host.clone(), num_predict).await;
+                            ws.handle_text(text.to_string()).await;
+                            if ws.options.flush_interval() != flush_interval {
+                                flush_interval = ws.options.flush_interval();
+                                inference_interval = time::interval_at(Instant::now() + flush_interval, flush_interval);
+                                inference_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+                            }
                         }
                         Some(Ok(Message::Close(_))) => {
                             tracing::info!("Received close message");
@@ -133,7 +720,7 @@ pub async fn ws_index(req: HttpRequest, stream: web::Payload, vlm_config: web::D
                         }
                         Some(Ok(Message::Ping(msg))) => {
                             inference_interval.reset();
-                            handle_ping(&mut session, msg).await;
+                            handle_ping(&mut ws.outbox.session, msg).await;
                             tracing::info!("Received ping message");
                         }
                         Some(Ok(Message::Pong(_))) => {
@@ -152,21 +739,101 @@ pub async fn ws_index(req: HttpRequest, stream: web::Payload, vlm_config: web::D
                         }
                     }
                 }
-                _ = inference_interval.tick().fuse() => {
-                    if let Err(e) = session.ping(b"ping").await {
+                batch_id = finished_rx.recv().fuse() => {
+                    if let Some(batch_id) = batch_id {
+                        ws.batch_finished(batch_id);
+                    }
+                }
+                _ = ping_interval.tick().fuse() => {
+                    if let Err(e) = ws.outbox.session.ping(b"ping").await {
                         tracing::error!("Error sending ping: {:?}", e);
                         break;
                     }
-                    if let Some(prompt) = last_prompt.clone() {
-                        tracing::info!("Inference interval fired: running handle_text with last prompt");
-                        handle_text(&mut session, &mut images, prompt.clone(), &mut last_prompt, model.clone(), ollama_host.clone(), num_predict).await;
+                }
+                _ = inference_interval.tick().fuse() => {
+                    if ws.prompt.is_some() {
+                        tracing::info!("Inference interval fired: sending buffered images with last prompt");
+                        ws.flush().await;
                     }
                 }
             }
         }
 
-        tracing::info!("Stream closed");
+        ws.abort();
+        METRICS.ws_sessions.dec();
+        tracing::info!(skipped_frames = ws.duplicates.skipped(), "Stream closed");
+        drop(ws);
+        if let Some(recording) = recording {
+            recording.finish(&pool, query.domain_id.as_deref().filter(|id| !id.is_empty())).await;
+        }
     });
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlm_config() -> config::Config {
+        let mut vlm_config = config::Config::for_tests();
+        vlm_config.allowed_models.push("qwen2.5vl:7b".to_string());
+        vlm_config
+    }
+
+    fn apply(changes: serde_json::Value) -> Result<EffectiveOptions, (&'static str, String)> {
+        let vlm_config = vlm_config();
+        let changes = serde_json::from_value::<SessionOptions>(changes).unwrap();
+        EffectiveOptions::defaults(&vlm_config).apply(changes, &vlm_config)
+    }
+
+    #[test]
+    fn numbers_are_clamped_to_their_limits() {
+        let options = apply(json!({
+            "num_predict": 100_000,
+            "num_ctx": 1,
+            "temperature": 5.0,
+            "top_p": -1.0,
+            "batch_size": 0,
+            "flush_interval_ms": 10,
+        })).unwrap();
+        assert_eq!(options.generate.num_predict, Some(NUM_PREDICT_RANGE.1));
+        assert_eq!(options.generate.num_ctx, Some(NUM_CTX_RANGE.0));
+        assert_eq!(options.generate.temperature, Some(2.0));
+        assert_eq!(options.generate.top_p, Some(0.0));
+        assert_eq!(options.batch_size, 1);
+        assert_eq!(options.flush_interval_ms, FLUSH_INTERVAL_RANGE.0);
+    }
+
+    #[test]
+    fn num_predict_accepts_unlimited() {
+        assert_eq!(apply(json!({ "num_predict": -1 })).unwrap().generate.num_predict, Some(-1));
+        assert_eq!(apply(json!({ "num_predict": -5 })).unwrap().generate.num_predict, Some(NUM_PREDICT_RANGE.0));
+    }
+
+    #[test]
+    fn unset_options_keep_their_value() {
+        let options = apply(json!({ "model": "qwen2.5vl:7b", "seed": 7, "stop": "\n" })).unwrap();
+        assert_eq!(options.model, "qwen2.5vl:7b");
+        assert_eq!(options.generate.seed, Some(7));
+        assert_eq!(options.generate.stop, vec!["\n".to_string()]);
+        assert_eq!(options.batch_size, 5);
+        assert_eq!(options.generate.num_predict, None);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert_eq!(apply(json!({ "model": "llava:34b" })).unwrap_err().0, "model_not_allowed");
+        assert_eq!(apply(json!({ "stop": ["a", "b", "c", "d", "e"] })).unwrap_err().0, "invalid_options");
+        assert_eq!(apply(json!({ "stop": [""] })).unwrap_err().0, "invalid_options");
+        assert_eq!(apply(json!({ "format": "xml" })).unwrap_err().0, "invalid_options");
+    }
+
+    #[test]
+    fn format_takes_json_or_a_schema() {
+        assert_eq!(apply(json!({ "format": "json" })).unwrap().format, Some(json!("json")));
+        let schema = json!({ "type": "object" });
+        assert_eq!(apply(json!({ "format": schema })).unwrap().format, Some(schema));
+        assert_eq!(apply(json!({ "format": "" })).unwrap().format, None);
+    }
+}
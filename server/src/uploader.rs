@@ -0,0 +1,234 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use posemesh_domain_http::DomainClient;
+use sqlx::PgPool;
+use tokio::sync::Semaphore;
+
+use crate::{cancel, domain::upload_for_job, lease, models::{Job, JobError, JobStatus}, pg::{self, JobNotifier}};
+
+pub struct Config {
+    pub concurrency: usize,
+    pub max_retries: u32,
+    pub backoff_base: u64,
+    pub backoff_max: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Config {
+            concurrency: std::env::var("UPLOAD_CONCURRENCY").unwrap_or("2".to_string()).parse::<usize>()?,
+            max_retries: std::env::var("UPLOAD_MAX_RETRIES").unwrap_or("5".to_string()).parse::<u32>()?,
+            backoff_base: std::env::var("UPLOAD_BACKOFF_BASE_MS").unwrap_or("1000".to_string()).parse::<u64>()?,
+            backoff_max: std::env::var("UPLOAD_BACKOFF_MAX_MS").unwrap_or("60000".to_string()).parse::<u64>()?,
+        })
+    }
+
+    /// Delay before retry number `retry`, doubling from `backoff_base` up to `backoff_max`.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.backoff_base.saturating_mul(2_u64.saturating_pow(retry));
+        Duration::from_millis(delay.min(self.backoff_max))
+    }
+}
+
+struct Uploader {
+    id: String,
+    config: Config,
+    pool: PgPool,
+    notifier: JobNotifier,
+    domain_client: DomainClient,
+    data_dir: String,
+    lease: Duration,
+}
+
+/// Spawns the loop that claims `uploading` jobs and uploads their output to the domain,
+/// running at most `concurrency` uploads at once.
+pub fn spawn(
+    config: Config,
+    lease_config: &lease::Config,
+    pool: PgPool,
+    notifier: JobNotifier,
+    domain_client: DomainClient,
+    data_dir: String,
+) {
+    let permits = Arc::new(Semaphore::new(config.concurrency));
+    let uploader = Arc::new(Uploader {
+        id: lease::worker_id("uploader"),
+        config,
+        pool,
+        notifier,
+        domain_client,
+        data_dir,
+        lease: lease_config.duration(),
+    });
+    tokio::spawn(async move {
+        tracing::info!(worker_id = %uploader.id, "Uploader started");
+        let mut events = uploader.notifier.subscribe();
+        loop {
+            let permit = permits.clone().acquire_owned().await.expect("Upload semaphore closed");
+            // Keep claiming while there are free slots, sleep once the queue is drained
+            let job = match pg::claim_upload(&uploader.pool, &uploader.id, uploader.lease).await {
+                Ok(Some(job)) => job,
+                Ok(None) => {
+                    drop(permit);
+                    events.wait_for(|n| n.status == JobStatus::Uploading).await;
+                    continue;
+                }
+                Err(e) => {
+                    drop(permit);
+                    tracing::error!("Failed to claim upload: {:?}", e);
+                    events.wait_for(|n| n.status == JobStatus::Uploading).await;
+                    continue;
+                }
+            };
+            let uploader = uploader.clone();
+            tokio::spawn(async move {
+                uploader.upload_job(job).await;
+                drop(permit);
+            });
+        }
+    });
+}
+
+/// Only transport failures are worth retrying, anything else will fail the same way again.
+fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            return err.is_connect() || err.is_timeout() || err.is_request() || err.is_body();
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl Uploader {
+    async fn upload_job(&self, job: Job) {
+        let job_id = &job.common.id;
+        let output_dir = format!("{}/output/{}", &self.data_dir, job_id);
+
+        // Jobs without output files have nothing to upload and complete right away
+        if tokio::fs::try_exists(&output_dir).await.unwrap_or(false) {
+            let res = tokio::select! {
+                res = self.upload_with_retries(&job, &output_dir) => res,
+                _ = cancel::wait_for_cancel(&self.pool, &self.notifier, job_id) => {
+                    cancel::finalize_cancel(&self.pool, &self.data_dir, job_id).await;
+                    return;
+                }
+                _ = lease::keep_alive(&self.pool, job_id, &self.id, self.lease) => {
+                    tracing::warn!(job_id = %job_id, "Abandoning upload after losing its lease");
+                    return;
+                }
+            };
+            if let Err(e) = res {
+                tracing::error!(job_id = %job_id, "Failed to upload job output: {:?}", e);
+                let err = JobError {
+                    code: "upload_failed".to_string(),
+                    message: e.to_string(),
+                };
+                match pg::fail_job(&self.pool, job_id, &err, &job.common.updated_at).await {
+                    Ok(Some(_)) => (),
+                    Ok(None) => tracing::warn!(job_id = %job_id, "Job was modified while uploading"),
+                    Err(e) => tracing::error!("Failed to fail job: {:?}", e),
+                }
+                return;
+            }
+        }
+
+        match pg::complete_job(&self.pool, job_id, &job.common.updated_at).await {
+            Ok(Some(_)) => tracing::info!(job_id = %job_id, "Job output uploaded"),
+            Ok(None) => tracing::warn!(job_id = %job_id, "Job was modified while uploading"),
+            Err(e) => tracing::error!("Failed to complete job: {:?}", e),
+        }
+    }
+
+    async fn upload_with_retries(&self, job: &Job, output_dir: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(domain_id) = job.common.domain_id.as_deref() else {
+            return Err("Job has no domain_id to upload its output to".into());
+        };
+        let mut retry = 0;
+        loop {
+            match upload_for_job(&self.domain_client, domain_id, output_dir).await {
+                Ok(_) => return Ok(()),
+                Err(e) if retry < self.config.max_retries && is_retryable(e.as_ref()) => {
+                    let delay = self.config.backoff(retry);
+                    retry += 1;
+                    tracing::warn!(job_id = %job.common.id, retry, "Upload failed, retrying in {:?}: {:?}", delay, e);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    fn config(backoff_base: u64, backoff_max: u64) -> Config {
+        Config { concurrency: 1, max_retries: 5, backoff_base, backoff_max }
+    }
+
+    /// Fails to connect, nothing listens on a port that was just released.
+    async fn connect_error() -> reqwest::Error {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        reqwest::get(format!("http://{}", addr)).await.unwrap_err()
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(reqwest::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Upload failed")
+        }
+    }
+
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_from_base() {
+        let config = config(1000, 60_000);
+        assert_eq!(config.backoff(0), Duration::from_millis(1000));
+        assert_eq!(config.backoff(1), Duration::from_millis(2000));
+        assert_eq!(config.backoff(3), Duration::from_millis(8000));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let config = config(1000, 60_000);
+        assert_eq!(config.backoff(6), Duration::from_millis(60_000));
+        assert_eq!(config.backoff(u32::MAX), Duration::from_millis(60_000));
+    }
+
+    #[tokio::test]
+    async fn retries_transport_errors() {
+        let err = connect_error().await;
+        assert!(err.is_connect());
+        assert!(is_retryable(&err));
+        // Errors from the domain client wrap the reqwest error
+        assert!(is_retryable(&Wrapped(connect_error().await)));
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let err: Box<dyn Error + Send + Sync> = "Job has no domain_id to upload its output to".into();
+        assert!(!is_retryable(err.as_ref()));
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "missing output");
+        assert!(!is_retryable(&err));
+        // Invalid requests fail the same way every time
+        let err = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert!(err.is_builder());
+        assert!(!is_retryable(&err));
+    }
+}
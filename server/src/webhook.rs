@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::PgPool;
+
+use crate::{models::{DeliveryStatus, JobStatus, WebhookDelivery}, pg::{self, JobNotifier}};
+
+/// How often the dispatcher looks for deliveries whose retry has come due.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_ERROR_LENGTH: usize = 1024;
+
+pub struct Config {
+    pub secret: Option<String>,
+    pub concurrency: usize,
+    pub timeout: u64,
+    pub max_attempts: i32,
+    pub backoff_base: u64,
+    pub backoff_max: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Config {
+            secret: std::env::var("WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty()),
+            concurrency: std::env::var("WEBHOOK_CONCURRENCY").unwrap_or("8".to_string()).parse::<usize>()?,
+            timeout: std::env::var("WEBHOOK_TIMEOUT").unwrap_or("10".to_string()).parse::<u64>()?,
+            max_attempts: std::env::var("WEBHOOK_MAX_ATTEMPTS").unwrap_or("10".to_string()).parse::<i32>()?,
+            backoff_base: std::env::var("WEBHOOK_BACKOFF_BASE_MS").unwrap_or("1000".to_string()).parse::<u64>()?,
+            backoff_max: std::env::var("WEBHOOK_BACKOFF_MAX_MS").unwrap_or("3600000".to_string()).parse::<u64>()?,
+        })
+    }
+
+    /// Delay after attempt number `attempt` failed, doubling from `backoff_base` up to `backoff_max`.
+    fn backoff(&self, attempt: i32) -> Duration {
+        let delay = self.backoff_base.saturating_mul(2_u64.saturating_pow(attempt.max(0) as u32));
+        Duration::from_millis(delay.min(self.backoff_max))
+    }
+}
+
+/// Removes `webhook_secret` from a job input, so that it is stored next to the job instead of
+/// in its `input`, which is returned by the API.
+pub fn take_secret(input: &mut serde_json::Value) -> Option<String> {
+    let secret = input.as_object_mut()?.remove("webhook_secret")?;
+    secret.as_str().filter(|secret| !secret.is_empty()).map(str::to_string)
+}
+
+/// Signs `{timestamp}.{body}` with HMAC-SHA256, as sent in `X-Webhook-Signature`:
+/// `sha256=` followed by the hex encoded digest.
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+struct Dispatcher {
+    config: Config,
+    client: reqwest::Client,
+    pool: PgPool,
+}
+
+/// Spawns the loop that sends queued webhook deliveries. Deliveries are queued by the database
+/// when a job with a `webhook_url` completes or fails, and retried with exponential backoff
+/// until the receiver answers with a 2xx or `max_attempts` is reached.
+pub fn spawn(config: Config, pool: PgPool, notifier: JobNotifier) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .build()?;
+    let dispatcher = Dispatcher { config, client, pool };
+    tokio::spawn(async move {
+        let mut events = notifier.subscribe();
+        // Deliveries stay claimed for longer than a request can take
+        let lease = Duration::from_secs(dispatcher.config.timeout * 2);
+        loop {
+            match pg::claim_webhook_deliveries(&dispatcher.pool, dispatcher.config.concurrency as i64, lease).await {
+                Ok(deliveries) if !deliveries.is_empty() => {
+                    futures::future::join_all(deliveries.iter().map(|delivery| dispatcher.deliver(delivery))).await;
+                    continue;
+                }
+                Ok(_) => (),
+                Err(e) => tracing::error!("Failed to claim webhook deliveries: {:?}", e),
+            }
+            tokio::select! {
+                _ = events.wait_for(|n| matches!(n.status, JobStatus::Completed | JobStatus::Failed)) => (),
+                _ = tokio::time::sleep(POLL_INTERVAL) => (),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Dispatcher {
+    async fn deliver(&self, delivery: &WebhookDelivery) {
+        let (response_status, error) = match self.send(delivery).await {
+            Ok(status) if status.is_success() => {
+                match pg::mark_webhook_delivered(&self.pool, delivery.id, status.as_u16() as i32).await {
+                    Ok(_) => tracing::info!(job_id = %delivery.job_id, delivery_id = delivery.id, "Webhook delivered"),
+                    Err(e) => tracing::error!("Failed to record webhook delivery: {:?}", e),
+                }
+                return;
+            }
+            Ok(status) => (Some(status.as_u16() as i32), format!("Receiver responded with {}", status)),
+            Err(e) => (e.status().map(|status| status.as_u16() as i32), e.to_string()),
+        };
+
+        let error: String = error.chars().take(MAX_ERROR_LENGTH).collect();
+        let retry_in = self.config.backoff(delivery.attempts);
+        tracing::warn!(job_id = %delivery.job_id, delivery_id = delivery.id, attempt = delivery.attempts + 1, "Webhook delivery failed: {}", error);
+        match pg::fail_webhook_attempt(&self.pool, delivery.id, response_status, &error, self.config.max_attempts, retry_in).await {
+            Ok(Some(delivery)) if delivery.status == DeliveryStatus::Failed => {
+                tracing::error!(job_id = %delivery.job_id, delivery_id = delivery.id, "Giving up on webhook after {} attempts", delivery.attempts);
+            }
+            Ok(_) => (),
+            Err(e) => tracing::error!("Failed to record webhook attempt: {:?}", e),
+        }
+    }
+
+    async fn send(&self, delivery: &WebhookDelivery) -> Result<reqwest::StatusCode, reqwest::Error> {
+        let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut request = self.client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", delivery.id.to_string())
+            .header("X-Webhook-Timestamp", timestamp.to_string());
+        // The job's own secret takes precedence over the global one
+        if let Some(secret) = delivery.secret.as_deref().or(self.config.secret.as_deref()) {
+            request = request.header("X-Webhook-Signature", sign(secret, timestamp, &body));
+        }
+        let resp = request.body(body).send().await?;
+        Ok(resp.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn sign_matches_known_signature() {
+        let body = br#"{"job_id":"abc","status":"completed"}"#;
+        assert_eq!(
+            sign("whsec_test", 1700000000, body),
+            "sha256=ac020822304f15d50f373098e033ae311a0e91db68f46ad6d1141d42b095a661",
+        );
+    }
+
+    #[test]
+    fn sign_covers_timestamp_and_body() {
+        let signature = sign("whsec_test", 1700000000, b"{}");
+        assert_ne!(signature, sign("whsec_test", 1700000001, b"{}"));
+        assert_ne!(signature, sign("whsec_test", 1700000000, b"{ }"));
+        assert_ne!(signature, sign("whsec_other", 1700000000, b"{}"));
+    }
+
+    #[test]
+    fn take_secret_removes_secret_from_input() {
+        let mut input = json!({ "prompt": "Describe the scene", "webhook_secret": "whsec_test" });
+        assert_eq!(take_secret(&mut input), Some("whsec_test".to_string()));
+        assert_eq!(input, json!({ "prompt": "Describe the scene" }));
+    }
+
+    #[test]
+    fn take_secret_removes_empty_secret_without_returning_it() {
+        let mut input = json!({ "prompt": "Describe the scene", "webhook_secret": "" });
+        assert_eq!(take_secret(&mut input), None);
+        assert_eq!(input, json!({ "prompt": "Describe the scene" }));
+    }
+
+    #[test]
+    fn take_secret_leaves_input_without_secret_alone() {
+        let mut input = json!({ "prompt": "Describe the scene" });
+        assert_eq!(take_secret(&mut input), None);
+        assert_eq!(input, json!({ "prompt": "Describe the scene" }));
+        assert_eq!(take_secret(&mut json!("not an object")), None);
+    }
+}
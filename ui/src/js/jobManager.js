@@ -84,6 +84,16 @@ export class JobManager {
     }
   }
 
+  subscribeToEvents(onEvent) {
+    const source = new EventSource(`${APP_CONFIG.serverUrl}/jobs/events`);
+    ['created', 'status_changed', 'output_ready', 'lagged'].forEach((type) => {
+      source.addEventListener(type, (event) => {
+        onEvent(type, JSON.parse(event.data));
+      });
+    });
+    return source;
+  }
+
   formatDate(dateString) {
     if (!dateString) return 'N/A';
     try {
@@ -11,11 +11,55 @@ window.openSubmitDialog = () => window.uiManager.openSubmitDialog();
 window.openRetryDialog = (jobId) => window.uiManager.openRetryDialog(jobId);
 window.openViewDialog = (jobId) => window.uiManager.openViewDialog(jobId);
 
-// Load jobs on page load
+// Load jobs on page load, then keep the list up to date from the server's job events
+let reloadTimer = null;
 document.addEventListener('DOMContentLoaded', function () {
   loadJobs();
+  window.jobManager.subscribeToEvents(applyJobEvent);
 });
 
+function applyJobEvent(type, event) {
+  const now = new Date().toISOString();
+  switch (type) {
+    case 'created':
+      window.uiManager.upsertJob({
+        id: event.id,
+        status: event.status,
+        created_at: now,
+        updated_at: now,
+      });
+      break;
+    case 'status_changed':
+      if (!window.uiManager.updateJobStatus(event.id, event.status, now)) {
+        // Jobs outside of the rendered list have nothing to update
+        return;
+      }
+      refreshViewedJob(event.id);
+      break;
+    case 'output_ready':
+      refreshViewedJob(event.id);
+      break;
+    case 'lagged':
+      // Some events were dropped, only a full reload brings the list back in sync
+      clearTimeout(reloadTimer);
+      reloadTimer = setTimeout(loadJobs, 500);
+      break;
+  }
+}
+
+// The output and error are only shown in the view dialog, fetch them if it is open on the job
+async function refreshViewedJob(jobId) {
+  if (window.uiManager.viewJobId !== jobId) return;
+  try {
+    const job = await window.jobManager.getJob(jobId);
+    if (window.uiManager.viewJobId === jobId) {
+      window.uiManager.populateViewForm(job);
+    }
+  } catch (error) {
+    window.uiManager.showStatus(error.message, 'error');
+  }
+}
+
 async function loadJobs() {
   try {
     const jobs = await window.jobManager.loadJobs();
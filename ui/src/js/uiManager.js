@@ -1,5 +1,6 @@
 export class UIManager {
   constructor() {
+    this.viewJobId = null;
     this.initializeEventListeners();
   }
 
@@ -39,21 +40,59 @@ export class UIManager {
     tbody.innerHTML = '';
 
     jobs.forEach((job) => {
-      const row = document.createElement('tr');
-      row.innerHTML = `
-        <td>${job.id}</td>
-        <td>${job.status || 'N/A'}</td>
-        <td>${this.formatDate(job.created_at)}</td>
-        <td>${this.formatDate(job.updated_at)}</td>
-        <td>
-          <button class="retry-btn" onclick="window.uiManager.openRetryDialog('${job.id}')" style="margin-right: 5px;">Retry</button>
-          <button class="btn btn-primary" onclick="window.uiManager.openViewDialog('${job.id}')">View</button>
-        </td>
-      `;
-      tbody.appendChild(row);
+      tbody.appendChild(this.createJobRow(job));
     });
   }
 
+  createJobRow(job) {
+    const row = document.createElement('tr');
+    row.dataset.jobId = job.id;
+    row.innerHTML = `
+      <td>${job.id}</td>
+      <td class="job-status">${job.status || 'N/A'}</td>
+      <td>${this.formatDate(job.created_at)}</td>
+      <td class="job-updated-at">${this.formatDate(job.updated_at)}</td>
+      <td>
+        <button class="retry-btn" onclick="window.uiManager.openRetryDialog('${job.id}')" style="margin-right: 5px;">Retry</button>
+        <button class="btn btn-primary" onclick="window.uiManager.openViewDialog('${job.id}')">View</button>
+      </td>
+    `;
+    return row;
+  }
+
+  findJobRow(jobId) {
+    const tbody = document.getElementById('jobs-tbody');
+    if (!tbody) return null;
+    return (
+      Array.from(tbody.rows).find((row) => row.dataset.jobId === jobId) || null
+    );
+  }
+
+  // Adds a job at the top of the list, or replaces its row if it is already listed
+  upsertJob(job) {
+    const tbody = document.getElementById('jobs-tbody');
+    if (!tbody) return;
+
+    const row = this.createJobRow(job);
+    const existing = this.findJobRow(job.id);
+    if (existing) {
+      existing.replaceWith(row);
+    } else {
+      tbody.insertBefore(row, tbody.firstChild);
+    }
+  }
+
+  // Applies a status change to a listed job, returns false if the job is not listed
+  updateJobStatus(jobId, status, updatedAt) {
+    const row = this.findJobRow(jobId);
+    if (!row) return false;
+
+    row.querySelector('.job-status').textContent = status || 'N/A';
+    row.querySelector('.job-updated-at').textContent =
+      this.formatDate(updatedAt);
+    return true;
+  }
+
   formatDate(dateString) {
     if (!dateString) return 'N/A';
     try {
@@ -200,6 +239,7 @@ export class UIManager {
 
   // View Dialog Methods
   openViewDialog(jobId) {
+    this.viewJobId = jobId;
     window.jobManager
       .getJob(jobId)
       .then((job) => {
@@ -247,6 +287,7 @@ export class UIManager {
     if (overlay) {
       overlay.style.display = 'none';
     }
+    this.viewJobId = null;
   }
 
   displayJsonField(fieldId, data) {
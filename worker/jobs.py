@@ -20,6 +20,17 @@ def get_next_job(conn):
             return job
         return None
 
+# Subscribe to the job status notifications sent by the server's database trigger
+def listen_for_jobs(conn):
+    conn.execute("LISTEN job_status")
+    conn.commit()
+
+# Block until a job becomes pending, falling back to a poll after `timeout` seconds
+def wait_for_pending_job(conn, timeout=30):
+    for notify in conn.notifies(timeout=timeout):
+        if json.loads(notify.payload).get("status") == "pending":
+            return
+
 # Finish processing
 def finish_processing(conn, job_id, output):
     with conn.cursor() as cur:
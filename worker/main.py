@@ -1,7 +1,6 @@
 # main.py
-import time
 import psycopg
-from jobs import get_next_job, fail_job, cancel_job
+from jobs import get_next_job, fail_job, cancel_job, listen_for_jobs, wait_for_pending_job
 from worker import process_job
 from logger_config import get_logger
 
@@ -14,6 +13,10 @@ POSTGRES_URL = os.environ.get("POSTGRES_URL")
 if not POSTGRES_URL:
     raise RuntimeError("POSTGRES_URL environment variable not set")
 
+# The server runs jobs itself unless JOB_EXECUTOR_ENABLED=false, two consumers would race for every job
+if os.environ.get("JOB_EXECUTOR_ENABLED", "true").lower() != "false":
+    raise RuntimeError("The server's job executor is enabled, set JOB_EXECUTOR_ENABLED=false on the server and the worker to run jobs here")
+
 def get_db_conn():
     return psycopg.connect(POSTGRES_URL)
 
@@ -25,6 +28,7 @@ logger = get_logger("dispatcher")
 
 def single_thread_main():
     conn = get_db_conn()
+    listen_for_jobs(conn)
     logger.info("Running in single-thread mode")
 
     while True:
@@ -36,7 +40,7 @@ def single_thread_main():
             except Exception as e:
                 logger.error("Error processing job", extra={"job_id": job['id'], "error": str(e)})
                 err = {
-                    "code": 100,
+                    "code": "inference_error",
                     "message": str(e)
                 }
                 fail_job(conn, job['id'], err)
@@ -45,20 +49,21 @@ def single_thread_main():
                 cancel_job(conn, job['id'])
                 exit(0)
         else:
-            time.sleep(2)
+            wait_for_pending_job(conn)
 
 # This is capped by postgres connection pool size, GPU memory, and CPU
 def multi_thread_main():
     logger.info("Running in multi-thread mode")
     with ThreadPoolExecutor(max_workers=MAX_WORKERS) as executor:
         main_conn = get_db_conn()
+        listen_for_jobs(main_conn)
         while True:
             job = get_next_job(main_conn)
             if job:
                 logger.info("Submitting job to worker", extra={"job_id": job['id']})
                 executor.submit(threaded_job, job)
             else:
-                time.sleep(2)
+                wait_for_pending_job(main_conn)
 
 def threaded_job(job):
     # Each thread has its own connection
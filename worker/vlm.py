@@ -2,7 +2,6 @@ import json
 import os
 import re
 import sys
-import requests
 from jobs import finish_processing, fail_job, complete_job
 from logger_config import get_logger
 from ollama import Client
@@ -74,12 +73,12 @@ def parse_image_timestamp(image_path):
         return match.group('ts')
     return ""
 
-def run_vlm_only(vlm_prompt, image_paths):
+def run_vlm_only(vlm_prompt, image_paths, vlm_model=None):
     """
     Run VLM inference on images without LLM temporal reasoning.
     Returns direct VLM responses for each image.
     """
-    vlm_model = os.environ.get("VLM_MODEL", "llava:7b")
+    vlm_model = vlm_model or os.environ.get("VLM_MODEL", "llava:7b")
     
     logger.info("Using VLM model: " + vlm_model)
     ensure_model_available(vlm_model)
@@ -108,13 +107,13 @@ def run_vlm_only(vlm_prompt, image_paths):
     }
 
 
-def run_inference(vlm_prompt, prompt, image_paths):
+def run_inference(vlm_prompt, prompt, image_paths, vlm_model=None, llm_model=None):
     start_image = None
     end_image = None
     
-    # Get model names from environment variables
-    vlm_model = os.environ.get("VLM_MODEL", "llava:7b")
-    llm_model = os.environ.get("LLM_MODEL", "llama3:latest")
+    # Models picked by the job, validated by the server, default to the environment
+    vlm_model = vlm_model or os.environ.get("VLM_MODEL", "llava:7b")
+    llm_model = llm_model or os.environ.get("LLM_MODEL", "llama3:latest")
     
     logger.info("Using VLM model: " + vlm_model)
     logger.info("Using LLM model: " + llm_model)
@@ -157,26 +156,6 @@ def run_inference(vlm_prompt, prompt, image_paths):
         "temporal_output": temporal_res.response
     }
 
-def send_webhook(webhook_url, job_id, data, error):
-    if webhook_url is None or webhook_url == "":
-        logger.warning("Webhook URL is empty")
-        return
-
-    payload = {
-        "job_id": job_id,
-        "data": data,
-        "error": error
-    }
-    try:
-        response = requests.post(webhook_url, json=payload, timeout=10)
-        if response.status_code != 200:
-            logger.error("Failed to send webhook: status_code=" + str(response.status_code) + " response=" + response.text)
-            raise Exception(f"Failed to send webhook: {response.text}")
-        else:
-            logger.info("Webhook sent successfully")
-    except Exception as e:
-        raise e
-
 def find_images(input_dir):
     image_paths = []
     valid_exts = ('.jpg', '.jpeg', '.png')
@@ -214,7 +193,7 @@ def run(conn, job, input_dir, output_dir):
     if len(image_paths) == 0:
         logger.warning("No images found: input_dir=" + input_dir)
         err = {
-            "code": 400,
+            "code": "no_images",
             "message": "No images found"
         }
         fail_job(conn, job['id'], err)
@@ -223,34 +202,19 @@ def run(conn, job, input_dir, output_dir):
     try:
         if job_type == 'vlm_only':
             # Direct VLM inference without LLM temporal reasoning
-            results = run_vlm_only(inputs['vlm_prompt'], image_paths)
+            results = run_vlm_only(inputs['vlm_prompt'], image_paths, inputs.get('vlm_model'))
         else:
             # Default: task_timing_v1 with LLM temporal reasoning
-            results = run_inference(inputs['vlm_prompt'], inputs['prompt'], image_paths)
+            results = run_inference(inputs['vlm_prompt'], inputs['prompt'], image_paths, inputs.get('vlm_model'), inputs.get('llm_model'))
     except Exception as e:
         logger.error("Error processing job", extra={"job_id": job['id'], "error": str(e)})
         err = {
-            "code": 100,
+            "code": "inference_error",
             "message": str(e)
         }
         fail_job(conn, job['id'], err)
-        try:
-            send_webhook(inputs['webhook_url'], job['id'], None, err)
-        except Exception as e:
-            logger.error("Failed to send webhook", extra={"job_id": job['id'], "error": str(e)})
         return
 
+    # The server delivers the webhook, if any, once the job is completed
     finish_processing(conn, job['id'], results)
-
-    try:
-        send_webhook(inputs['webhook_url'], job['id'], results, None)
-    except Exception as e:
-        logger.error("Failed to send webhook", extra={"job_id": job['id'], "error": str(e)})
-        err = {
-            "code": 200,
-            "message": str(e)
-        }
-        fail_job(conn, job['id'], err)
-        return
-
     complete_job(conn, job['id'])